@@ -0,0 +1,356 @@
+//! Parsing of the `#[mpi(...)]` attributes
+
+use proc_macro2::Ident;
+use syn::{Attribute, Field, LitInt, LitStr, Member, Path, Result, Token, Type, WherePredicate, bracketed, parenthesized};
+use syn::ext::IdentExt;
+use syn::parse::{Parse, ParseStream, Parser};
+use syn::punctuated::Punctuated;
+
+/// Attributes of the derived type
+#[derive(Default)]
+pub struct ContainerAttrs {
+    /// `#[mpi(cached)]`: the datatype is built once per process and handed out by reference
+    pub cached: bool,
+    /// `#[mpi(bound = "T: Trait, ...")]`: where predicates replacing the inferred `Equivalence` bounds
+    pub bound: Option<Vec<WherePredicate>>,
+    /// `#[mpi(transparent)]`: the type forwards to the Equivalence implementation of its only field
+    pub transparent: bool,
+    /// `#[mpi(bytes)]`: the type is sent as `size_of::<Self>()` bytes, padding included, for plain data
+    pub bytes: bool,
+    /// `#[mpi(active = field)]`: the given field of a union is always the active one, and is sent with its typemap
+    pub active: Option<Ident>,
+    /// `#[mpi(view(Name = [field, ...], ...))]`: datatypes of subsets of the fields
+    pub views: Vec<View>,
+    /// `#[mpi(field_datatypes)]`: a datatype of each field alone, like a view of this field
+    pub field_datatypes: bool,
+    /// `#[mpi(reduce_with = path::to::fn)]`: reduction combining values with `fn(&Self, &mut Self)`
+    pub reduce_with: Option<Path>,
+    /// `#[mpi(version = N)]`: version of the layout, by default the highest `since` of the fields
+    pub version: Option<LitInt>,
+}
+
+/// Named subset of the fields of a structure, sent with the extent of the whole structure
+pub struct View {
+    pub name: Ident,
+    pub fields: Vec<Member>,
+}
+
+impl Parse for View {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let name = input.parse()?;
+        input.parse::<Token![=]>()?;
+        let content;
+        bracketed!(content in input);
+        let fields = Punctuated::<Member, Token![,]>::parse_terminated(&content)?;
+        Ok(View { name, fields: fields.into_iter().collect() })
+    }
+}
+
+impl ContainerAttrs {
+    pub fn parse(attrs: &[Attribute]) -> Result<Self> {
+        let mut container_attrs = ContainerAttrs::default();
+        parse_mpi_attrs(attrs, |key, input| {
+            if key == "cached" {
+                container_attrs.cached = true;
+            } else if key == "transparent" {
+                container_attrs.transparent = true;
+            } else if key == "bytes" {
+                container_attrs.bytes = true;
+            } else if key == "active" {
+                container_attrs.active = Some(parse_value(input)?);
+            } else if key == "view" {
+                let content;
+                parenthesized!(content in input);
+                container_attrs.views.extend(Punctuated::<View, Token![,]>::parse_terminated(&content)?);
+            } else if key == "field_datatypes" {
+                container_attrs.field_datatypes = true;
+            } else if key == "reduce_with" {
+                container_attrs.reduce_with = Some(parse_value(input)?);
+            } else if key == "version" {
+                container_attrs.version = Some(parse_version(input)?);
+            } else if key == "bound" {
+                input.parse::<Token![=]>()?;
+                let predicates = input.parse::<LitStr>()?.parse_with(Punctuated::<WherePredicate, Token![,]>::parse_terminated)?;
+                container_attrs.bound = Some(predicates.into_iter().collect());
+            } else {
+                return Err(unknown_attr(key));
+            }
+            if container_attrs.cached && container_attrs.transparent {
+                return Err(syn::Error::new(key.span(), "`cached` and `transparent` cannot be combined, as transparent types use the datatype of their field"));
+            }
+            if container_attrs.bytes && container_attrs.transparent {
+                return Err(syn::Error::new(key.span(), "`bytes` and `transparent` cannot be combined"));
+            }
+            if (container_attrs.has_views() || container_attrs.reduce_with.is_some() || container_attrs.version.is_some())
+                && (container_attrs.bytes || container_attrs.transparent) {
+                return Err(syn::Error::new(key.span(),
+                    "Views, field datatypes, reductions and versions cannot be combined with `bytes` or `transparent`"));
+            }
+            Ok(())
+        })?;
+        Ok(container_attrs)
+    }
+
+    /// Whether datatypes of subsets of the fields are generated, by views or for each field
+    pub fn has_views(&self) -> bool {
+        !self.views.is_empty() || self.field_datatypes
+    }
+
+    /// Whether items built from the fields of a structure are requested, which forwarding types lack
+    pub fn uses_fields(&self) -> bool {
+        self.cached || self.has_views() || self.reduce_with.is_some() || self.version.is_some()
+    }
+}
+
+/// Attributes of a field
+#[derive(Default)]
+pub struct FieldAttrs {
+    /// `#[mpi(skip)]`: the field is left out of the datatype, receivers leave it untouched
+    pub skip: bool,
+    /// `#[mpi(datatype = path::to::fn)]`: the datatype of the field is returned by this function
+    pub datatype: Option<Path>,
+    /// `#[mpi(as = Type)]`: the field is sent with the datatype of this type of the same layout
+    pub as_type: Option<Type>,
+    /// `#[mpi(flatten)]`: the fields of this derived struct are part of the typemap of the outer one
+    pub flatten: bool,
+    /// `#[mpi(reduce = op)]`: operation combining the values of the field in a reduction
+    pub reduce: Option<Ident>,
+    /// `#[mpi(since = N)]`: version of the layout in which the field was added
+    pub since: Option<LitInt>,
+    /// `#[mpi(default = path::to::fn)]`: value of the field when it is missing from an older version
+    pub default: Option<Path>,
+}
+
+/// Operations of `#[mpi(reduce = op)]`
+pub const REDUCE_OPS: &[&str] = &["sum", "prod", "max", "min", "band", "bor", "bxor", "land", "lor", "first"];
+
+impl FieldAttrs {
+    pub fn parse(attrs: &[Attribute]) -> Result<Self> {
+        let mut field_attrs = FieldAttrs::default();
+        let mut overrides = 0;
+        parse_mpi_attrs(attrs, |key, input| {
+            if key == "skip" {
+                field_attrs.skip = true;
+            } else if key == "datatype" {
+                field_attrs.datatype = Some(parse_value(input)?);
+                overrides += 1;
+            } else if key == "as" {
+                field_attrs.as_type = Some(parse_value(input)?);
+                overrides += 1;
+            } else if key == "flatten" {
+                field_attrs.flatten = true;
+                overrides += 1;
+            } else if key == "reduce" {
+                let op: Ident = parse_value(input)?;
+                if !REDUCE_OPS.iter().any(|known| op == known) {
+                    return Err(syn::Error::new(op.span(),
+                        format!("Unknown reduction `{}`, expected one of: {}", op, REDUCE_OPS.join(", "))));
+                }
+                field_attrs.reduce = Some(op);
+            } else if key == "since" {
+                field_attrs.since = Some(parse_version(input)?);
+            } else if key == "default" {
+                field_attrs.default = Some(parse_value(input)?);
+            } else {
+                return Err(unknown_attr(key));
+            }
+            if field_attrs.skip && overrides > 0 || overrides > 1 {
+                return Err(syn::Error::new(key.span(), "Only one of `skip`, `datatype`, `as` and `flatten` can be used on a field"));
+            }
+            if field_attrs.skip && field_attrs.reduce.is_some() {
+                return Err(syn::Error::new(key.span(), "Skipped fields are not sent, so they cannot be reduced"));
+            }
+            if field_attrs.skip && field_attrs.since.is_some() {
+                return Err(syn::Error::new(key.span(), "Skipped fields are not sent, so they have no version"));
+            }
+            Ok(())
+        })?;
+        Ok(field_attrs)
+    }
+
+    /// Parse the attributes of each field, reporting the errors of all the fields at once
+    ///
+    /// The fields are then handed to each stage with their attributes, in the order of declaration.
+    pub fn parse_fields<'a>(fields: impl IntoIterator<Item = &'a Field>) -> std::result::Result<Vec<(&'a Field, FieldAttrs)>, Vec<syn::Error>> {
+        let mut parsed = Vec::new();
+        let mut errors = Vec::new();
+        for f in fields {
+            match FieldAttrs::parse(&f.attrs) {
+                Ok(attrs) => parsed.push((f, attrs)),
+                Err(error) => errors.push(error),
+            }
+        }
+        if errors.is_empty() {
+            Ok(parsed)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Whether the field has no `#[mpi(...)]` attributes
+    pub fn is_empty(&self) -> bool {
+        !self.skip && self.datatype.is_none() && self.as_type.is_none() && !self.flatten && self.reduce.is_none()
+            && self.since.is_none() && self.default.is_none()
+    }
+}
+
+/// Call `parse_item` with the key of each comma separated item of the `#[mpi(...)]` attributes
+///
+/// `parse_item` parses the rest of the item, e.g. `= value`, from the given stream.
+fn parse_mpi_attrs<F>(attrs: &[Attribute], mut parse_item: F) -> Result<()>
+where
+    F: FnMut(&Ident, ParseStream) -> Result<()>,
+{
+    for attr in attrs {
+        if attr.path.segments.len() != 1 || attr.path.segments[0].ident != "mpi" {
+            continue;
+        }
+        let parser = |input: ParseStream| -> Result<()> {
+            let content;
+            parenthesized!(content in input);
+            while !content.is_empty() {
+                // Keys may be keywords, such as `as`
+                let key = content.call(Ident::parse_any)?;
+                parse_item(&key, &content)?;
+                if content.is_empty() {
+                    break;
+                }
+                content.parse::<Token![,]>()?;
+            }
+            Ok(())
+        };
+        parser.parse2(attr.tts.clone())?;
+    }
+    Ok(())
+}
+
+/// Parse `= value`, where the value may also be quoted in a string literal like in serde
+fn parse_value<T: Parse>(input: ParseStream) -> Result<T> {
+    input.parse::<Token![=]>()?;
+    if input.peek(LitStr) {
+        input.parse::<LitStr>()?.parse()
+    } else {
+        input.parse()
+    }
+}
+
+/// Parse `= N`, where `N` is a version number starting at 1
+fn parse_version(input: ParseStream) -> Result<LitInt> {
+    let version: LitInt = parse_value(input)?;
+    if version.value() == 0 || version.value() > u64::from(u32::MAX) {
+        return Err(syn::Error::new(version.span(), "Versions start at 1 and must fit in a `u32`"));
+    }
+    Ok(version)
+}
+
+fn unknown_attr(key: &Ident) -> syn::Error {
+    syn::Error::new(key.span(), format!("Unknown mpi attribute `{}`", key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quote::quote;
+    use syn::parse_quote;
+
+    fn container(attrs: &[Attribute]) -> std::result::Result<ContainerAttrs, String> {
+        ContainerAttrs::parse(attrs).map_err(|error| error.to_string())
+    }
+
+    fn field(attrs: &[Attribute]) -> std::result::Result<FieldAttrs, String> {
+        FieldAttrs::parse(attrs).map_err(|error| error.to_string())
+    }
+
+    #[test]
+    fn container_attrs() {
+        let attrs = container(&[
+            parse_quote!(#[repr(C)]),
+            parse_quote!(#[mpi(cached, view(Position = [x, 1]), field_datatypes)]),
+            parse_quote!(#[mpi(bound = "T: Clone, U: Copy", reduce_with = "merge", version = 3)]),
+        ]).unwrap();
+        assert!(attrs.cached && attrs.field_datatypes && attrs.has_views() && attrs.uses_fields());
+        assert!(!attrs.transparent && !attrs.bytes && attrs.active.is_none());
+        assert_eq!(attrs.views.len(), 1);
+        assert_eq!(attrs.views[0].name, "Position");
+        assert_eq!(attrs.views[0].fields.iter().map(|member| quote!(#member).to_string()).collect::<Vec<_>>(), ["x", "1"]);
+        assert_eq!(attrs.bound.map(|bound| bound.len()), Some(2));
+        assert!(attrs.reduce_with.is_some_and(|function| function.is_ident("merge")));
+        assert_eq!(attrs.version.map(|version| version.value()), Some(3));
+
+        let attrs = container(&[parse_quote!(#[mpi(active = int)])]).unwrap();
+        assert!(attrs.active.is_some_and(|active| active == "int"));
+        assert!(!container(&[]).unwrap().uses_fields());
+    }
+
+    #[test]
+    fn container_attr_errors() {
+        assert_eq!(container(&[parse_quote!(#[mpi(cahced)])]).err().unwrap(), "Unknown mpi attribute `cahced`");
+        assert_eq!(container(&[parse_quote!(#[mpi(cached)]), parse_quote!(#[mpi(transparent)])]).err().unwrap(),
+            "`cached` and `transparent` cannot be combined, as transparent types use the datatype of their field");
+        assert_eq!(container(&[parse_quote!(#[mpi(transparent, bytes)])]).err().unwrap(), "`bytes` and `transparent` cannot be combined");
+        for attr in [parse_quote!(#[mpi(bytes, field_datatypes)]), parse_quote!(#[mpi(version = 2, transparent)]),
+            parse_quote!(#[mpi(reduce_with = f, bytes)]), parse_quote!(#[mpi(view(V = [a]), transparent)])] {
+            assert_eq!(container(&[attr]).err().unwrap(),
+                "Views, field datatypes, reductions and versions cannot be combined with `bytes` or `transparent`");
+        }
+        assert_eq!(container(&[parse_quote!(#[mpi(version = 0)])]).err().unwrap(), "Versions start at 1 and must fit in a `u32`");
+        assert_eq!(container(&[parse_quote!(#[mpi(version = 4294967296)])]).err().unwrap(),
+            "Versions start at 1 and must fit in a `u32`");
+        assert!(container(&[parse_quote!(#[mpi(cached bytes)])]).is_err());
+    }
+
+    #[test]
+    fn field_attrs() {
+        assert!(field(&[parse_quote!(#[doc = "Not an mpi attribute"])]).unwrap().is_empty());
+        assert!(field(&[parse_quote!(#[mpi(skip)])]).unwrap().skip);
+        assert!(field(&[parse_quote!(#[mpi(as = "u64")])]).unwrap().as_type.is_some());
+        assert!(field(&[parse_quote!(#[mpi(as = [u8; 4])])]).unwrap().as_type.is_some());
+        assert!(field(&[parse_quote!(#[mpi(datatype = self::datatype)])]).unwrap().datatype.is_some());
+        assert!(field(&[parse_quote!(#[mpi(flatten)])]).unwrap().flatten);
+
+        let attrs = field(&[parse_quote!(#[mpi(reduce = sum, since = 2)]), parse_quote!(#[mpi(default = zero)])]).unwrap();
+        assert!(attrs.reduce.is_some_and(|op| op == "sum"));
+        assert_eq!(attrs.since.map(|since| since.value()), Some(2));
+        assert!(attrs.default.is_some_and(|default| default.is_ident("zero")));
+        assert!(!attrs.skip && attrs.as_type.is_none() && attrs.datatype.is_none() && !attrs.flatten);
+    }
+
+    #[test]
+    fn field_attr_errors() {
+        let overrides = "Only one of `skip`, `datatype`, `as` and `flatten` can be used on a field";
+        for attrs in [
+            [parse_quote!(#[mpi(skip, as = u8)])],
+            [parse_quote!(#[mpi(datatype = f, as = u8)])],
+            [parse_quote!(#[mpi(flatten, skip)])],
+            [parse_quote!(#[mpi(flatten, datatype = f)])],
+        ] {
+            assert_eq!(field(&attrs).err().unwrap(), overrides);
+        }
+        assert_eq!(field(&[parse_quote!(#[mpi(as = u8)]), parse_quote!(#[mpi(as = u16)])]).err().unwrap(), overrides);
+        assert_eq!(field(&[parse_quote!(#[mpi(reduce = sum, skip)])]).err().unwrap(), "Skipped fields are not sent, so they cannot be reduced");
+        assert_eq!(field(&[parse_quote!(#[mpi(skip, since = 2)])]).err().unwrap(), "Skipped fields are not sent, so they have no version");
+        assert_eq!(field(&[parse_quote!(#[mpi(reduce = add)])]).err().unwrap(),
+            format!("Unknown reduction `add`, expected one of: {}", REDUCE_OPS.join(", ")));
+        assert_eq!(field(&[parse_quote!(#[mpi(skp)])]).err().unwrap(), "Unknown mpi attribute `skp`");
+        assert!(field(&[parse_quote!(#[mpi(as)])]).is_err());
+    }
+
+    #[test]
+    fn fields_attrs() {
+        let fields: syn::FieldsNamed = parse_quote!({
+            #[mpi(skip)] a: u8,
+            #[mpi(skp)] b: u8,
+            c: u8,
+            #[mpi(as = u8, flatten)] d: u8,
+        });
+        let errors = FieldAttrs::parse_fields(&fields.named).err().unwrap();
+        assert_eq!(errors.iter().map(|error| error.to_string()).collect::<Vec<_>>(), [
+            "Unknown mpi attribute `skp`",
+            "Only one of `skip`, `datatype`, `as` and `flatten` can be used on a field",
+        ]);
+
+        let fields: syn::FieldsUnnamed = parse_quote!((#[mpi(skip)] u8, u16));
+        let parsed = FieldAttrs::parse_fields(&fields.unnamed).unwrap();
+        assert_eq!(parsed.iter().map(|(_, attrs)| attrs.skip).collect::<Vec<_>>(), [true, false]);
+    }
+}
@@ -6,164 +6,1940 @@
 //! - arrays of those types
 //! - tuples of those types
 //!
-//! Type aliases cannot be supported, as they are defined outside of the derived type.
+//! Types may be written in parentheses, as qualified paths such as `<T as Trait>::Assoc`, or be
+//! substituted by `macro_rules!` (e.g. `$t:ty`), including inside arrays and tuples.
 //!
-//! `enum`s are not supported yet, `union`s may never be.
+//! Array lengths may be const generic parameters (`[T; N]`). As rsmpi has no large-count constructors,
+//! building the datatype panics if a merged block has more elements than `mpi::Count` can represent.
+//!
+//! Zero-sized fields (`()`, `[T; 0]`, `PhantomData<T>` and `PhantomPinned`) are not transmitted,
+//! and unit structs are equivalent to an empty datatype.
+//!
+//! Structs get an associated constant `MPI_LAYOUT` describing the fields that are sent, as
+//! `(name, offset, size, element type, element count)` tuples, which does not require MPI.
+//! Their associated function `verify_equivalence()` checks the committed datatype against this layout.
+//! `MPI_FINGERPRINT` hashes this layout at compile time, with the fingerprints of the derived field types,
+//! and the collective `check_fingerprint(&comm)` reports the ranks of a communicator whose build has
+//! another layout or datatype. Transparent types, enums and unions get them too, hashing their fields
+//! and variants.
+//!
+//! Fields marked `#[mpi(skip)]` are not transmitted, receivers leave them untouched.
+//!
+//! Fields of derived struct types marked `#[mpi(flatten)]` have their fields spliced into the typemap
+//! of the outer struct, which then builds a single datatype instead of one per nested struct.
+//!
+//! Type aliases of arrays and tuples cannot be resolved, as they are defined outside of the derived
+//! type. Such fields, and fields of foreign types not implementing `Equivalence`, can be sent with:
+//! - `#[mpi(as = Type)]`, using the datatype of a type with the same layout, e.g. `u64` for `Wrapping<u64>`
+//! - `#[mpi(datatype = path::to::function)]`, using the datatype returned by a function
+//!
+//! `enum`s are supported when they have an explicit integer representation (e.g. `#[repr(u8)]`
+//! or `#[repr(C, u8)]`), or `#[repr(C)]`, whose discriminant is a C `int`. Field-less enums are then
+//! equivalent to that integer, and enums carrying data are sent as their tag followed by the bytes of
+//! the payload, whose layout is defined by [RFC 2195](https://rust-lang.github.io/rfcs/2195-really-tagged-unions.html).
+//! The fields of their variants must be plain data, as for `#[mpi(bytes)]`.
+//! The associated function `receive_checked` is generated to receive them without trusting the sender.
+//!
+//! For generic types, each transmitted field type using a type parameter is required to implement
+//! `Equivalence`. `#[mpi(bound = "T: Trait, ...")]` on the type replaces these inferred bounds.
+//!
+//! With `#[mpi(cached)]` on the type, its datatype is built once per process (and per instantiation
+//! of generic types) and handed out as a `DatatypeRef<'static>`, instead of being built and freed on
+//! each call of `equivalent_datatype()`.
+//!
+//! `#[mpi(view(Name = [field, ...]))]` on a struct generates an associated function `name_datatype()`
+//! returning the datatype of a subset of its fields, with the extent of the whole struct.
+//! `#[mpi(field_datatypes)]` generates such a function `field_datatype_<field>()` for each field.
+//!
+//! Fields marked `#[mpi(reduce = op)]` (`sum`, `prod`, `max`, `min`, `band`, `bor`, `bxor`, `land`,
+//! `lor` or `first`), or a type marked `#[mpi(reduce_with = path::to::function)]`, generate an associated
+//! function `reduce_operation()` returning a commutative `UserOperation` reducing values field by field.
+//!
+//! Fields marked `#[mpi(since = N)]` were added in version `N` of a `#[repr(C)]` struct. `MPI_VERSION` is then its
+//! current version (or the one given by `#[mpi(version = N)]`), `equivalent_datatype_for_version(v)`
+//! returns the datatype of the fields of version `v`, and `fill_missing_fields(v)` sets the newer
+//! fields of a received value from `Default` or the function given by `#[mpi(default = path)]`.
+//!
+//! Newtypes declared `#[repr(transparent)]` or marked `#[mpi(transparent)]` forward to the
+//! `Equivalence` implementation of their only field, and have the same `Out` type.
+//!
+//! Structs marked `#[mpi(bytes)]` are sent as `size_of::<Self>()` bytes, for homogeneous systems.
+//! They must be `Copy`, and their fields must be plain data: primitive numbers, arrays and tuples of
+//! plain data and other `#[mpi(bytes)]` types. Their padding bytes are sent too, uninitialized as
+//! they may be, like the bytes of the payloads of enums and of the inactive fields of unions.
+//!
+//! `#[repr(C)]` `union`s with `Copy` plain data fields are sent as bytes, as their active field is unknown.
+//! `#[mpi(active = field)]` sends the given field with its typemap instead, when it is always the active one.
+//!
+//! `#[derive(MpiPack)]` packs structs with `String`, `Vec` and `Box` fields with `MPI_Pack`: the fields
+//! of a fixed size with a datatype, then each sequence as its length and elements. It generates
+//! `to_packed()`, `from_packed()`, `send_packed()` and `receive_packed()`, which probes the size of the
+//! message. Fields of types deriving `MpiPack` are marked `#[mpi(flatten)]`.
 
 extern crate proc_macro;
 
+mod attr;
+mod pack;
+mod reduce;
+mod version;
+
 use proc_macro2::{Ident, TokenStream};
-use syn::{Data, DeriveInput, Fields, Index, parse_macro_input};
+use syn::{Attribute, Data, DataEnum, DataStruct, DataUnion, DeriveInput, Fields, Generics, Index, parse_macro_input};
+use syn::ext::IdentExt;
 use syn::spanned::Spanned;
 
+use attr::{ContainerAttrs, FieldAttrs, View};
+
 use quote::{quote, quote_spanned};
 
-#[proc_macro_derive(Equivalence)]
+#[proc_macro_derive(Equivalence, attributes(mpi))]
 pub fn derive_equivalence(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     // Parse the input tokens into a syntax tree.
     let input = parse_macro_input!(input as DeriveInput);
 
-    let name = input.ident;
+    let expanded = match input.data {
+        Data::Struct(ref data) => impl_struct_equivalence(&input, data),
+        Data::Enum(ref data) => impl_enum_equivalence(&input, data),
+        Data::Union(ref data) => impl_union_equivalence(&input, data),
+    };
+
+    // Report all errors at once
+    let expanded = expanded.unwrap_or_else(|errors| errors.iter().map(syn::Error::to_compile_error).collect());
+
+    // Hand the output tokens back to the compiler.
+    proc_macro::TokenStream::from(expanded)
+}
+
+#[proc_macro_derive(MpiPack, attributes(mpi))]
+pub fn derive_mpi_pack(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    let expanded = pack::impl_pack(&input).unwrap_or_else(|errors| errors.iter().map(syn::Error::to_compile_error).collect());
+
+    proc_macro::TokenStream::from(expanded)
+}
+
+/// Implement the Equivalence trait for a structure, using its typemap
+///
+/// Transparent structures forward to their field instead, unless `#[repr(transparent)]` is
+/// combined with `#[mpi(cached)]`, views, `reduce_with` or `version`, and `#[mpi(bytes)]`
+/// structures are sent as bytes.
+fn impl_struct_equivalence(input: &DeriveInput, data: &DataStruct) -> Result<TokenStream, Vec<syn::Error>> {
+    let (attrs, fields) = match (ContainerAttrs::parse(&input.attrs), FieldAttrs::parse_fields(&data.fields)) {
+        (Ok(attrs), Ok(fields)) => (attrs, fields),
+        // Report the errors of the types of the fields too
+        (Err(error), Ok(fields)) => return Err(std::iter::once(error).chain(create_struct_datatype(&fields, None).err().into_iter().flatten()).collect()),
+        (attrs, fields) => return Err(attrs.err().into_iter().chain(fields.err().into_iter().flatten()).collect()),
+    };
+    if let Some(ref active) = attrs.active {
+        return Err(vec![syn::Error::new(active.span(), "`active` can only be used on unions")]);
+    }
+    if attrs.bytes {
+        // Plain data is `Copy`, which also rules out types with a destructor
+        let mut input = input.clone();
+        input.generics.make_where_clause().predicates.push(syn::parse_quote!(Self: ::core::marker::Copy));
+        let equivalence = impl_user_datatype_equivalence(&input, &attrs, create_bytes_datatype(&input.generics, &fields)?, &[]);
+        let plain_data = impl_plain_data(&input);
+        let layout = impl_layout(&input, &fields);
+        let field_types = fields.iter().map(|(f, _)| &f.ty).collect::<Vec<_>>();
+        let fingerprint = impl_fingerprint(&input, quote!(Self::MPI_LAYOUT), &field_types);
+        Ok(quote! {
+            #equivalence
+            #plain_data
+            #layout
+            #fingerprint
+        })
+    } else if attrs.transparent || !attrs.uses_fields() && !fields_use_typemap(&fields) && has_repr(&input.attrs, "transparent") {
+        Ok(impl_transparent_equivalence(input, &attrs, transparent_field(input, &fields)?))
+    } else {
+        // Report the errors of every stage, those of the types of the fields being reported with the datatype
+        let (datatype, leaf_types, mut errors) = match create_struct_datatype(&fields, None) {
+            Ok((datatype, leaf_types)) => (datatype, leaf_types, Vec::new()),
+            Err(errors) => (TokenStream::new(), Vec::new(), errors),
+        };
+        let mut stage = |result: Result<TokenStream, Vec<syn::Error>>| result.unwrap_or_else(|stage_errors| {
+            errors.extend(stage_errors);
+            TokenStream::new()
+        });
+        let views = stage(impl_views(input, &attrs, &fields));
+        let reduce = stage(reduce::impl_reduce_operation(input, &attrs, &fields));
+        let versions = stage(version::impl_versions(input, &attrs, &fields, &leaf_types));
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        let equivalence = impl_user_datatype_equivalence(input, &attrs, datatype, &leaf_types);
+        let layout = impl_layout(input, &fields);
+        let verify = impl_verify_equivalence(input, &attrs, &fields, &leaf_types);
+        // The datatypes given by functions have no type to describe them
+        let sent_types = fields.iter()
+            .filter(|(_, attrs)| !attrs.skip && attrs.datatype.is_none())
+            .map(|(f, attrs)| attrs.as_type.as_ref().unwrap_or(&f.ty))
+            .collect::<Vec<_>>();
+        let fingerprint = impl_fingerprint(input, quote!(Self::MPI_LAYOUT), &sent_types);
+        let flatten = impl_flatten(input, &attrs, &fields, &leaf_types);
+        Ok(quote! {
+            #equivalence
+            #views
+            #reduce
+            #layout
+            #verify
+            #fingerprint
+            #versions
+            #flatten
+        })
+    }
+}
+
+/// Whether a field is reduced or versioned, which requires the typemap of the structure
+fn fields_use_typemap(fields: &[(&syn::Field, FieldAttrs)]) -> bool {
+    fields.iter().any(|(_, attrs)| attrs.reduce.is_some() || attrs.since.is_some())
+}
+
+/// Generate an associated function returning the datatype of each view of a structure
+///
+/// The datatype of a view covers only its fields, but has the extent of the whole structure, so
+/// that a buffer of the structure can be sent as a buffer of the view without copying.
+/// `#[mpi(field_datatypes)]` adds a view `field_datatype_<field>` of each transmitted field.
+fn impl_views(input: &DeriveInput, attrs: &ContainerAttrs, fields: &[(&syn::Field, FieldAttrs)]) -> Result<TokenStream, Vec<syn::Error>> {
+    let name = &input.ident;
+    let vis = &input.vis;
+    let mut errors = Vec::new();
+    let mut views = Vec::new();
+    for view in attrs.views.iter() {
+        errors.extend(check_view_fields(name, fields, view));
+        let function = Ident::new(&format!("{}_datatype", snake_case(&view.name)), view.name.span());
+        views.push((function, view.fields.clone()));
+    }
+    if attrs.field_datatypes {
+        for (i, (f, field_attrs)) in fields.iter().enumerate() {
+            if is_zero_sized(&f.ty) || field_attrs.skip {
+                continue;
+            }
+            let member = match f.ident {
+                Some(ref ident) => syn::Member::Named(ident.clone()),
+                None => syn::Member::Unnamed(Index::from(i)),
+            };
+            // Raw identifiers such as `r#type` are not valid in the middle of a name
+            let function = Ident::new(&format!("field_datatype_{}", field_member(f, i).trim_start_matches("r#")), f.span());
+            views.push((function, vec![member]));
+        }
+    }
+
+    let mut functions = Vec::new();
+    for (function, members) in views {
+        let (datatype, leaf_types) = subset_datatype(fields, &members);
+        let generics = add_trait_bounds(&input.generics, attrs, &leaf_types);
+        let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+        let doc = format!("Datatype of the field{} `{}` of `{}`, with the extent of `{}`",
+            if members.len() == 1 { "" } else { "s" }, quote!(#(#members),*), name, name);
+        functions.push(quote! {
+            impl #impl_generics #name #ty_generics #where_clause {
+                #[doc = #doc]
+                #vis fn #function() -> mpi::datatype::UserDatatype {
+                    #datatype
+                }
+            }
+        });
+    }
+    if errors.is_empty() {
+        Ok(quote! { #(#functions)* })
+    } else {
+        Err(errors)
+    }
+}
+
+/// Generate the associated constant `MPI_LAYOUT` describing the fields of the typemap without MPI
+///
+/// Each field that is sent is described by its name, offset, size, the type of its elements and
+/// their count, which is the length of arrays of leaf types and 1 otherwise.
+fn impl_layout(input: &DeriveInput, fields: &[(&syn::Field, FieldAttrs)]) -> TokenStream {
+    let name = &input.ident;
+    let vis = &input.vis;
     let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let fields = fields.iter().enumerate().filter_map(|(i, (f, attrs))| {
+        if attrs.skip || is_zero_sized(&f.ty) {
+            return None;
+        }
+        let member = field_member(f, i);
+        let offset = match f.ident {
+            Some(ref field_name) => offset_of_field(quote!(Self), quote!(#field_name), f.span()),
+            None => {
+                let field_index = Index::from(i);
+                offset_of_field(quote!(Self), quote!(#field_index), f.span())
+            }
+        };
+        let field_type = &f.ty;
+        let sent_type = attrs.as_type.as_ref().unwrap_or(field_type);
+        let (elem, count) = match ungroup(sent_type) {
+            syn::Type::Array(array) if matches!(ungroup(&array.elem), syn::Type::Path(_) | syn::Type::Macro(_)) => {
+                let elem = ungroup(&array.elem);
+                let len = &array.len;
+                (type_name(elem), quote!(#len))
+            }
+            t => (type_name(t), quote!(1)),
+        };
+        Some(quote! {
+            (#member, #offset, ::core::mem::size_of::<#field_type>(), #elem, #count)
+        })
+    });
+    let doc = format!("Layout of the fields of `{}` that are sent: (name, offset, size, element type, element count)", name);
+    quote! {
+        impl #impl_generics #name #ty_generics #where_clause {
+            #[doc = #doc]
+            #vis const MPI_LAYOUT: &'static [(&'static str, usize, usize, &'static str, usize)] = &[
+                #(#fields),*
+            ];
+        }
+    }
+}
 
-    // Generate the expression defining the MPI Datatype of the whole structure
-    let datatype = create_struct_datatype(&name, &input.data);
+/// Generate the associated constant `MPI_FINGERPRINT` hashing a layout, and a collective check of it
+///
+/// The hash is FNV-1a, which is simple enough to be evaluated at compile time and does not depend on
+/// the build. `check_fingerprint()` gathers the fingerprints of all the ranks of a communicator to
+/// detect ranks running a build in which the type has another layout.
+///
+/// `layout` is a constant expression of the same type as `MPI_LAYOUT`, which structs hash, while
+/// other types describe their fields and variants with it. The fingerprints of the types deriving
+/// `Equivalence` that make up the `field_types` are hashed too, as their layout is part of the layout
+/// of the type. Type parameters cannot be told apart in constants, so `check_fingerprint()` also
+/// hashes the committed datatype, which is built from the datatypes of the type arguments.
+fn impl_fingerprint(input: &DeriveInput, layout: TokenStream, field_types: &[&syn::Type]) -> TokenStream {
+    let name = &input.ident;
+    let vis = &input.vis;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let type_name = name.to_string();
+    let doc = format!("Hash of the name of `{}`, its size and the layout of its fields, identical on ranks sharing its layout", name);
 
-    // Implement the Equivalence trait
-    let expanded = quote! {
-        unsafe impl #impl_generics mpi::datatype::traits::Equivalence for #name #ty_generics #where_clause {
-            type Out = mpi::datatype::UserDatatype;
-            fn equivalent_datatype() -> Self::Out {
-                #datatype
+    let mut nested = Vec::new();
+    for t in field_types {
+        path_types(t, &mut nested);
+    }
+    let mut names = Vec::new();
+    nested.retain(|t| {
+        let name = quote!(#t).to_string();
+        let first = !names.contains(&name);
+        names.push(name);
+        first
+    });
+
+    let hash = quote! {
+        const fn hash(mut hash: u64, bytes: &[u8]) -> u64 {
+            let mut i = 0;
+            while i < bytes.len() {
+                hash = (hash ^ bytes[i] as u64).wrapping_mul(0x0100_0000_01b3);
+                i += 1;
             }
+            hash
         }
     };
+    let fingerprint = quote! {{
+        // The fingerprints of the types of the fields, which are 0 for the types not deriving
+        // `Equivalence`, as inherent constants take precedence over the ones of traits
+        trait __MpiNotDerived {
+            const MPI_FINGERPRINT: u64 = 0;
+        }
+        impl<T: ?Sized> __MpiNotDerived for T {}
+        let nested: &[u64] = &[#(<#nested>::MPI_FINGERPRINT),*];
+        let layout: &[(&str, usize, usize, &str, usize)] = #layout;
 
-    // Hand the output tokens back to the compiler.
-    proc_macro::TokenStream::from(expanded)
+        // In its own scope, so that the functions do not shadow the items of the fields
+        {
+            #hash
+            // Strings are prefixed by their length, so that the boundaries between them are part of the hash
+            const fn hash_str(fingerprint: u64, string: &str) -> u64 {
+                hash(hash(fingerprint, &(string.len() as u64).to_le_bytes()), string.as_bytes())
+            }
+
+            let mut fingerprint = hash_str(0xcbf2_9ce4_8422_2325, #type_name);
+            fingerprint = hash(fingerprint, &(::core::mem::size_of::<Self>() as u64).to_le_bytes());
+            let mut i = 0;
+            while i < layout.len() {
+                let (name, offset, size, element_type, count) = layout[i];
+                fingerprint = hash_str(fingerprint, name);
+                fingerprint = hash(fingerprint, &(offset as u64).to_le_bytes());
+                fingerprint = hash(fingerprint, &(size as u64).to_le_bytes());
+                fingerprint = hash_str(fingerprint, element_type);
+                fingerprint = hash(fingerprint, &(count as u64).to_le_bytes());
+                i += 1;
+            }
+            let mut i = 0;
+            while i < nested.len() {
+                fingerprint = hash(fingerprint, &nested[i].to_le_bytes());
+                i += 1;
+            }
+            fingerprint
+        }
+    }};
+    let check = quote! {
+        #hash
+        /// Hash the constructor of a datatype and the datatypes it is built from, down to named datatypes
+        fn hash_datatype(mut fingerprint: u64, mut datatype: mpi::ffi::MPI_Datatype, free: bool) -> u64 {
+            let (mut integers, mut addresses, mut datatypes, mut combiner) = (0, 0, 0, 0);
+            unsafe {
+                mpi::ffi::MPI_Type_get_envelope(datatype, &mut integers, &mut addresses, &mut datatypes, &mut combiner);
+            }
+            // Only named datatypes are not built from other datatypes, and they must not be freed
+            if datatypes == 0 {
+                let mut name = vec![0; mpi::ffi::MPI_MAX_OBJECT_NAME as usize];
+                let mut length = 0;
+                unsafe {
+                    mpi::ffi::MPI_Type_get_name(datatype, name.as_mut_ptr(), &mut length);
+                }
+                fingerprint = hash(fingerprint, &(length as u64).to_le_bytes());
+                return name[..length as usize].iter().fold(fingerprint, |fingerprint, &c| hash(fingerprint, &[c as u8]));
+            }
+            let mut integer_values = vec![0; integers as usize];
+            let mut address_values = vec![0; addresses as usize];
+            let mut inner = Vec::with_capacity(datatypes as usize);
+            unsafe {
+                mpi::ffi::MPI_Type_get_contents(datatype, integers, addresses, datatypes,
+                    integer_values.as_mut_ptr(), address_values.as_mut_ptr(), inner.as_mut_ptr());
+                inner.set_len(datatypes as usize);
+            }
+            fingerprint = hash(fingerprint, &(combiner as i64).to_le_bytes());
+            for value in integer_values {
+                fingerprint = hash(fingerprint, &(value as i64).to_le_bytes());
+            }
+            for value in address_values {
+                fingerprint = hash(fingerprint, &(value as i64).to_le_bytes());
+            }
+            for datatype in inner {
+                fingerprint = hash_datatype(fingerprint, datatype, true);
+            }
+            if free {
+                unsafe {
+                    mpi::ffi::MPI_Type_free(&mut datatype);
+                }
+            }
+            fingerprint
+        }
+
+        let datatype = <Self as mpi::datatype::Equivalence>::equivalent_datatype();
+        let fingerprint = hash_datatype(Self::MPI_FINGERPRINT, mpi::raw::AsRaw::as_raw(&datatype), false);
+        let mut fingerprints = vec![0u64; mpi::topology::Communicator::size(comm) as usize];
+        mpi::collective::CommunicatorCollectives::all_gather_into(comm, &fingerprint, &mut fingerprints[..]);
+        let divergent = fingerprints.iter().enumerate()
+            .filter(|(_, fingerprint)| **fingerprint != fingerprints[0])
+            .map(|(rank, _)| rank.to_string())
+            .collect::<Vec<_>>();
+        if divergent.is_empty() {
+            Ok(())
+        } else {
+            Err(format!(
+                "The layout of `{}` on ranks {} differs from rank 0: fingerprint {:#018x} on rank 0, {:#018x} on this rank",
+                ::core::any::type_name::<Self>(), divergent.join(", "), fingerprints[0], fingerprint,
+            ))
+        }
+    };
+
+    quote! {
+        impl #impl_generics #name #ty_generics #where_clause {
+            #[doc = #doc]
+            #vis const MPI_FINGERPRINT: u64 = #fingerprint;
+
+            /// Check that all the ranks of `comm` share the layout of this type, naming the ranks that do not
+            ///
+            /// The fingerprint of the committed datatype is gathered, which extends `MPI_FINGERPRINT`
+            /// with the datatypes of the type arguments of generic types.
+            /// This is a collective operation: all the ranks must call it, and get the same result.
+            #vis fn check_fingerprint(comm: &impl mpi::topology::Communicator) -> Result<(), String>
+            where
+                Self: mpi::datatype::Equivalence,
+            {
+                #check
+            }
+        }
+    }
 }
 
-/// Create a MPI Datatype for a structure
-fn create_struct_datatype(struct_name: &Ident, data: &Data) -> TokenStream {
-    match *data {
-        Data::Struct(ref data) => {
-            match data.fields {
-                Fields::Named(ref fields) => {
-                    let len = fields.named.len() as i32;
-                    let offsets = fields.named.iter().map(|f| {
-                        let field_name = f.ident.as_ref().unwrap();
-                        let offset = offset_of_field(quote!(#struct_name), quote!(#field_name), f.span());
-                        quote_spanned! { f.span() => #offset as mpi::Address }
-                    });
-                    let types = fields.named.iter().map(|f| {
-                        get_datatype(&f.ty)
-                    });
-                    quote! {
-                        mpi::datatype::UserDatatype::structured(
-                            #len,
-                            &[1; #len as usize],
-                            &[#(#offsets,)*],
-                            &[#(#types,)*],
-                        )
-                    }
-                },
-                Fields::Unnamed(ref fields) => {
-                    let len = fields.unnamed.len() as i32;
-                    let offsets = fields.unnamed.iter().enumerate().map(|(i, f)| {
-                        let field_index = Index::from(i);
-                        let offset = offset_of_field(quote!(#struct_name), quote!(#field_index), f.span());
-                        quote_spanned! { f.span() => #offset as mpi::Address }
-                    });
-                    let types = fields.unnamed.iter().map(|f| {
-                        get_datatype(&f.ty)
-                    });
-                    quote! {
-                        mpi::datatype::UserDatatype::structured(
-                            #len,
-                            &[1; #len as usize],
-                            &[#(#offsets,)*],
-                            &[#(#types,)*],
-                        )
+/// Collect the types that may derive `Equivalence` making up a type, through arrays and tuples
+fn path_types<'a>(t: &'a syn::Type, types: &mut Vec<&'a syn::Type>) {
+    match ungroup(t) {
+        syn::Type::Array(array) => path_types(&array.elem, types),
+        syn::Type::Tuple(tuple) => tuple.elems.iter().for_each(|t| path_types(t, types)),
+        t @ syn::Type::Path(_) | t @ syn::Type::Macro(_) if !is_zero_sized(t) => types.push(t),
+        _ => {}
+    }
+}
+
+/// Name of a type in layouts and fingerprints, which does not depend on how the type is written
+///
+/// Paths are named by their last segment, without lifetimes, so that `std::primitive::u32` is `u32`,
+/// and the tokens are spaced in the same way whatever the compiler.
+fn type_name(t: &syn::Type) -> String {
+    match ungroup(t) {
+        syn::Type::Path(path) => {
+            let segments = path.path.segments.iter().collect::<Vec<_>>();
+            match path.qself {
+                Some(ref qself) => {
+                    let rest = segments[qself.position..].iter().map(|segment| segment_name(segment)).collect::<Vec<_>>().join("::");
+                    match qself.position {
+                        0 => format!("<{}>::{}", type_name(&qself.ty), rest),
+                        position => format!("<{} as {}>::{}", type_name(&qself.ty), segment_name(segments[position - 1]), rest),
                     }
-                },
-                Fields::Unit => unimplemented!()
+                }
+                None => segments.last().map_or_else(String::new, |segment| segment_name(segment)),
             }
-        },
-        Data::Enum(_) | Data::Union(_) => unimplemented!("Enums and unions are not implemented yet"),
+        }
+        syn::Type::Array(array) => {
+            let len = &array.len;
+            format!("[{}; {}]", type_name(&array.elem), tokens_name(quote!(#len)))
+        }
+        syn::Type::Tuple(tuple) if tuple.elems.len() == 1 => format!("({},)", type_name(&tuple.elems[0])),
+        syn::Type::Tuple(tuple) => format!("({})", tuple.elems.iter().map(type_name).collect::<Vec<_>>().join(", ")),
+        t => tokens_name(quote!(#t)),
     }
 }
 
-/// Get the MPI Datatype of types implementing the Equivalence trait, or create a MPI Datatype for arrays and tuples of those types
-fn get_datatype(t: &syn::Type) -> TokenStream {
-    match t {
-        // Recursion for arrays
+/// Name of a segment of a path, with its type arguments but without its lifetimes
+fn segment_name(segment: &syn::PathSegment) -> String {
+    let ident = &segment.ident;
+    match segment.arguments {
+        syn::PathArguments::None => ident.to_string(),
+        syn::PathArguments::AngleBracketed(ref arguments) => {
+            let arguments = arguments.args.iter().filter_map(|argument| match argument {
+                syn::GenericArgument::Lifetime(_) => None,
+                syn::GenericArgument::Type(t) => Some(type_name(t)),
+                syn::GenericArgument::Binding(binding) => Some(format!("{} = {}", binding.ident, type_name(&binding.ty))),
+                argument => Some(tokens_name(quote!(#argument))),
+            }).collect::<Vec<_>>();
+            if arguments.is_empty() {
+                ident.to_string()
+            } else {
+                format!("{}<{}>", ident, arguments.join(", "))
+            }
+        }
+        syn::PathArguments::Parenthesized(_) => tokens_name(quote!(#segment)),
+    }
+}
+
+/// Text of tokens, with a space only between identifiers and literals that would otherwise merge
+fn tokens_name(tokens: TokenStream) -> String {
+    let mut name = String::new();
+    let mut word = false;
+    for token in tokens {
+        match token {
+            proc_macro2::TokenTree::Group(group) => {
+                let (open, close) = match group.delimiter() {
+                    proc_macro2::Delimiter::Parenthesis => ("(", ")"),
+                    proc_macro2::Delimiter::Bracket => ("[", "]"),
+                    proc_macro2::Delimiter::Brace => ("{", "}"),
+                    proc_macro2::Delimiter::None => ("", ""),
+                };
+                name.push_str(open);
+                name.push_str(&tokens_name(group.stream()));
+                name.push_str(close);
+                word = false;
+            }
+            proc_macro2::TokenTree::Punct(punct) => {
+                name.push(punct.as_char());
+                word = false;
+            }
+            token => {
+                if word {
+                    name.push(' ');
+                }
+                name.push_str(&token.to_string());
+                word = true;
+            }
+        }
+    }
+    name
+}
+
+/// Generate the hidden associated function `__mpi_flatten` used by `#[mpi(flatten)]` fields of this type
+///
+/// It pushes the typemap entries of the fields at `offset`, then calls `then` with the typemap while
+/// the datatypes of the entries are alive, so that the outer structure can build its datatype.
+/// It is generated once the datatype of the structure is built, so its typemap has no errors. Its type
+/// parameter has a reserved name, as it is declared alongside the type parameters of the structure.
+fn impl_flatten(input: &DeriveInput, attrs: &ContainerAttrs, fields: &[(&syn::Field, FieldAttrs)], leaf_types: &[syn::Type]) -> TokenStream {
+    let name = &input.ident;
+    let vis = &input.vis;
+    let generics = add_trait_bounds(&input.generics, attrs, leaf_types);
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let (entries, _) = struct_typemap(fields, None, Some(&quote!(offset)), quote!(then(typemap)))
+        .expect("the typemap of the structure has no errors");
+
+    quote! {
+        impl #impl_generics #name #ty_generics #where_clause {
+            #[doc(hidden)]
+            #[allow(dead_code)]
+            #vis fn __mpi_flatten<__MpiRet>(
+                offset: usize,
+                typemap: &mut Vec<(usize, usize, Option<usize>, mpi::ffi::MPI_Datatype)>,
+                then: impl FnOnce(&mut Vec<(usize, usize, Option<usize>, mpi::ffi::MPI_Datatype)>) -> __MpiRet,
+            ) -> __MpiRet {
+                #entries
+            }
+        }
+    }
+}
+
+/// Generate an associated function `verify_equivalence()` checking the committed datatype at runtime
+///
+/// The datatype must have a lower bound of 0 and the extent of the type, and the data sent for each
+/// field, given by the true extent of its datatype alone, must lie within the bytes of the field.
+/// Fields whose type is made of primitives must send exactly the bytes of these primitives.
+fn impl_verify_equivalence(input: &DeriveInput, attrs: &ContainerAttrs, fields: &[(&syn::Field, FieldAttrs)], leaf_types: &[syn::Type]) -> TokenStream {
+    let name = &input.ident;
+    let vis = &input.vis;
+    let generics = add_trait_bounds(&input.generics, attrs, leaf_types);
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let checks = fields.iter().enumerate().filter_map(|(i, (f, attrs))| {
+        if attrs.skip || is_zero_sized(&f.ty) {
+            return None;
+        }
+        let member = match f.ident {
+            Some(ref ident) => syn::Member::Named(ident.clone()),
+            None => syn::Member::Unnamed(Index::from(i)),
+        };
+        let (datatype, _) = subset_datatype(fields, std::slice::from_ref(&member));
+        let field_name = field_member(f, i);
+        let offset = offset_of_field(quote!(Self), quote!(#member), f.span());
+        let field_type = &f.ty;
+        // The fields of flattened fields are checked by the datatype of their type
+        let sent_type = Some(attrs.as_type.as_ref().unwrap_or(field_type)).filter(|_| !attrs.flatten);
+        let check_size = sent_type.and_then(primitives_size).map(|expected| quote! {
+            let expected = (#expected) as mpi::Address;
+            if size != expected {
+                errors.push(format!("field `{}` sends {} bytes, instead of the {} bytes of its type", #field_name, size, expected));
+            }
+        });
+        Some(quote! {{
+            let (_, _, true_lb, true_extent, size) = __mpi_extents(&#datatype);
+            let (offset, field_size) = (#offset as mpi::Address, ::core::mem::size_of::<#field_type>() as mpi::Address);
+            if size > 0 && (true_lb < offset || true_lb + true_extent > offset + field_size) {
+                errors.push(format!("field `{}` is sent from bytes {}..{}, outside of its bytes {}..{}",
+                    #field_name, true_lb, true_lb + true_extent, offset, offset + field_size));
+            }
+            #check_size
+        }})
+    });
+    let doc = format!("Check the layout of the committed datatype of `{}` against the layout of the type", name);
+
+    // Lower bound, extent, true lower bound, true extent and size of a datatype, with a reserved name
+    // as the functions of the datatypes of the fields are in its scope
+    let extents = quote! {
+        fn __mpi_extents<D>(datatype: &D) -> (mpi::Address, mpi::Address, mpi::Address, mpi::Address, mpi::Address)
+        where
+            D: mpi::raw::AsRaw<Raw = mpi::ffi::MPI_Datatype>,
+        {
+            let (mut lb, mut extent, mut true_lb, mut true_extent, mut size) = (0, 0, 0, 0, 0);
+            unsafe {
+                mpi::ffi::MPI_Type_get_extent(datatype.as_raw(), &mut lb, &mut extent);
+                mpi::ffi::MPI_Type_get_true_extent(datatype.as_raw(), &mut true_lb, &mut true_extent);
+                mpi::ffi::MPI_Type_size(datatype.as_raw(), &mut size);
+            }
+            (lb, extent, true_lb, true_extent, size as mpi::Address)
+        }
+    };
+    let check_type = quote! {
+        let (lb, extent, _, _, _) = __mpi_extents(&<Self as mpi::datatype::Equivalence>::equivalent_datatype());
+        if lb != 0 || extent != ::core::mem::size_of::<Self>() as mpi::Address {
+            errors.push(format!("the datatype spans bytes {}..{}, instead of the {} bytes of the type",
+                lb, lb + extent, ::core::mem::size_of::<Self>()));
+        }
+    };
+
+    quote! {
+        impl #impl_generics #name #ty_generics #where_clause {
+            #[doc = #doc]
+            ///
+            /// The datatype must span the size of the type from offset 0, and the data sent for each
+            /// field must lie within the bytes of the field. Fields made of primitives, possibly in
+            /// arrays and tuples, must send all the bytes of these primitives. Mismatches are
+            /// described in the error.
+            #vis fn verify_equivalence() -> Result<(), String>
+            where
+                Self: mpi::datatype::Equivalence,
+            {
+                #extents
+
+                let mut errors: Vec<String> = Vec::new();
+                #check_type
+                #(#checks)*
+
+                if errors.is_empty() {
+                    Ok(())
+                } else {
+                    Err(format!("Invalid datatype for `{}`: {}", ::core::any::type_name::<Self>(), errors.join(", ")))
+                }
+            }
+        }
+    }
+}
+
+/// Generate the size of the primitives making up a type, which has no padding between them
+///
+/// Returns `None` for types that are not made of primitives, arrays and tuples, whose data may not
+/// be all of their bytes.
+fn primitives_size(t: &syn::Type) -> Option<TokenStream> {
+    match ungroup(t) {
         syn::Type::Array(array) => {
+            let elem = primitives_size(&array.elem)?;
             let len = &array.len;
-            let element_userdatatype = get_datatype(array.elem.as_ref());
-            quote_spanned! { array.span() =>
-                &mpi::datatype::UserDatatype::contiguous(#len, #element_userdatatype)
-            }
+            Some(quote!((#elem) * (#len)))
         }
-        // Recursion for tuples
         syn::Type::Tuple(tuple) => {
-            let len = tuple.elems.len() as i32;
-            let offsets = tuple.elems.iter().enumerate().map(|(i, f)| {
-                let field_index = Index::from(i);
-                let offset = offset_of_field(quote!(#tuple), quote!(#field_index), f.span());
-                quote_spanned! { f.span() =>
-                    #offset as mpi::Address
+            let elems = tuple.elems.iter().map(primitives_size).collect::<Option<Vec<_>>>()?;
+            Some(quote!(0 #(+ #elems)*))
+        }
+        syn::Type::Path(path) if path.qself.is_none() && path.path.leading_colon.is_none() && path.path.segments.len() == 1 => {
+            let segment = path.path.segments.first()?.into_value();
+            let is_primitive = segment.ident == "bool" || PLAIN_PRIMITIVES.iter().any(|primitive| segment.ident == primitive);
+            if is_primitive && segment.arguments.is_empty() {
+                Some(quote!(::core::mem::size_of::<#t>()))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Check that the fields of a view exist, are transmitted, and appear once
+fn check_view_fields(name: &Ident, fields: &[(&syn::Field, FieldAttrs)], view: &View) -> Vec<syn::Error> {
+    let mut errors = Vec::new();
+    for (i, member) in view.fields.iter().enumerate() {
+        let key = quote!(#member).to_string();
+        if view.fields[..i].iter().any(|previous| quote!(#previous).to_string() == key) {
+            errors.push(syn::Error::new_spanned(member, format!("Field `{}` appears twice in view `{}`", key, view.name)));
+            continue;
+        }
+        match fields.iter().enumerate().find(|(index, (f, _))| field_member(f, *index) == key) {
+            None => errors.push(syn::Error::new_spanned(member, format!("`{}` has no field `{}`", name, key))),
+            Some((_, (_, attrs))) if attrs.skip => errors.push(syn::Error::new_spanned(member,
+                format!("Field `{}` is marked `#[mpi(skip)]`, so it cannot be part of a view", key))),
+            Some(_) => {}
+        }
+    }
+    errors
+}
+
+/// Name or index of a field, as written to access it
+fn field_member(f: &syn::Field, index: usize) -> String {
+    match f.ident {
+        Some(ref ident) => ident.to_string(),
+        None => Index::from(index).index.to_string(),
+    }
+}
+
+/// Convert a `CamelCase` name to `snake_case`, without the `r#` of raw identifiers
+fn snake_case(ident: &Ident) -> String {
+    let mut snake = String::new();
+    for (i, c) in ident.unraw().to_string().chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            snake.push('_');
+        }
+        snake.extend(c.to_lowercase());
+    }
+    snake
+}
+
+/// Field of a transparent structure, and the type given by `#[mpi(as = Type)]` on it
+struct TransparentField<'a> {
+    index: usize,
+    field: &'a syn::Field,
+    as_type: Option<syn::Type>,
+}
+
+/// Find the only field of a transparent structure that is neither zero-sized nor skipped
+fn transparent_field<'a>(input: &DeriveInput, fields: &[(&'a syn::Field, FieldAttrs)]) -> Result<TransparentField<'a>, Vec<syn::Error>> {
+    let mut errors = Vec::new();
+    let mut sent = Vec::new();
+    for (index, (f, attrs)) in fields.iter().enumerate() {
+        match attrs {
+            attrs if attrs.skip || is_zero_sized(&f.ty) => {}
+            FieldAttrs { datatype: Some(function), .. } => errors.push(syn::Error::new_spanned(function,
+                "`datatype` cannot be used on the field of a transparent type, as the type of the datatype is unknown; \
+                 use `#[mpi(as = Type)]` instead")),
+            FieldAttrs { reduce: Some(op), .. } => errors.push(syn::Error::new(op.span(),
+                "Reductions are not generated for transparent types, reduce their field instead")),
+            FieldAttrs { since: Some(since), .. } => errors.push(syn::Error::new(since.span(),
+                "Transparent types have the versions of their field")),
+            attrs => sent.push(TransparentField { index, field: f, as_type: attrs.as_type.clone() }),
+        }
+    }
+    if sent.len() != 1 && errors.is_empty() {
+        errors.push(syn::Error::new(input.ident.span(),
+            "A transparent type must have exactly one field that is neither zero-sized nor marked `#[mpi(skip)]`"));
+    }
+    if errors.is_empty() {
+        Ok(sent.remove(0))
+    } else {
+        Err(errors)
+    }
+}
+
+/// Implement the Equivalence trait by forwarding to the type of the field of a transparent structure
+///
+/// No datatype is built: `Out` is the one of the field, so newtypes cost nothing more than their field.
+fn impl_transparent_equivalence(input: &DeriveInput, attrs: &ContainerAttrs, transparent: TransparentField) -> TokenStream {
+    let name = &input.ident;
+    let TransparentField { index, field, as_type } = transparent;
+    let forward = as_type.as_ref().unwrap_or(&field.ty);
+    let generics = add_trait_bounds(&input.generics, attrs, std::slice::from_ref(forward));
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    // The layout of `#[repr(transparent)]` types is guaranteed, a single field of the same size is at offset 0
+    let mut layout_checks = vec![check_same_size(&syn::parse_quote!(Self), &field.ty,
+        "A transparent type must have the size of its field")];
+    if let Some(ref as_type) = as_type {
+        layout_checks.push(check_same_size(&field.ty, as_type, AS_SIZE_MESSAGE));
+    }
+
+    let member = field_member(field, index);
+    let field_type = &field.ty;
+    let forward_name = type_name(forward);
+    let fingerprint = impl_fingerprint(input, quote! {
+        &[(#member, 0, ::core::mem::size_of::<#field_type>(), #forward_name, 1)]
+    }, &[forward]);
+
+    quote! {
+        unsafe impl #impl_generics mpi::datatype::traits::Equivalence for #name #ty_generics #where_clause {
+            type Out = <#forward as mpi::datatype::Equivalence>::Out;
+            fn equivalent_datatype() -> Self::Out {
+                #(#layout_checks)*
+                <#forward as mpi::datatype::Equivalence>::equivalent_datatype()
+            }
+        }
+
+        #fingerprint
+    }
+}
+
+/// Check whether the representation of a type includes `#[repr(<name>)]`
+fn has_repr(attrs: &[Attribute], name: &str) -> bool {
+    attrs.iter().any(|attr| match attr.interpret_meta() {
+        Some(syn::Meta::List(ref list)) if list.ident == "repr" => list.nested.iter().any(|nested| match nested {
+            syn::NestedMeta::Meta(syn::Meta::Word(ident)) => ident == name,
+            _ => false,
+        }),
+        _ => false,
+    })
+}
+
+/// Reject the container attributes that only apply to structures
+fn check_struct_only_attrs(input: &DeriveInput, attrs: &ContainerAttrs) -> Result<(), Vec<syn::Error>> {
+    if attrs.bytes || attrs.transparent || attrs.has_views() || attrs.reduce_with.is_some() || attrs.version.is_some() {
+        Err(vec![syn::Error::new(input.ident.span(),
+            "`bytes`, `transparent`, `view`, `field_datatypes`, `reduce_with` and `version` are only supported on structs")])
+    } else {
+        Ok(())
+    }
+}
+
+/// Implement the Equivalence trait with the expression `datatype` evaluating to a `UserDatatype`
+///
+/// With `#[mpi(cached)]`, the datatype is built and committed on first use, then handed out as a
+/// `DatatypeRef<'static>`. Initialisation is thread-safe, and generic types get one datatype per
+/// instantiation, which requires them to be `'static`. Cached datatypes are never freed: they are
+/// released by `MPI_Finalize`, and must not be used after it.
+fn impl_user_datatype_equivalence(input: &DeriveInput, attrs: &ContainerAttrs, datatype: TokenStream, leaf_types: &[syn::Type]) -> TokenStream {
+    let name = &input.ident;
+    let mut generics = add_trait_bounds(&input.generics, attrs, leaf_types);
+
+    if !attrs.cached {
+        let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+        return quote! {
+            unsafe impl #impl_generics mpi::datatype::traits::Equivalence for #name #ty_generics #where_clause {
+                type Out = mpi::datatype::UserDatatype;
+                fn equivalent_datatype() -> Self::Out {
+                    #datatype
+                }
+            }
+        };
+    }
+
+    // Const parameters change the datatype as much as type parameters
+    let is_generic = generics.params.iter().any(|param| !matches!(param, syn::GenericParam::Lifetime(_)));
+    let lookup = if !is_generic {
+        quote! {
+            static CACHE: ::std::sync::OnceLock<__MpiCached> = ::std::sync::OnceLock::new();
+            CACHE.get_or_init(|| __MpiCached::new(build())).0
+        }
+    } else {
+        // Statics are shared between the instantiations of generic functions, so index them by type
+        generics.make_where_clause().predicates.push(syn::parse_quote!(Self: 'static));
+        quote! {
+            static CACHE: ::std::sync::OnceLock<::std::sync::Mutex<::std::collections::HashMap<::core::any::TypeId, __MpiCached>>> =
+                ::std::sync::OnceLock::new();
+            let cache = || CACHE.get_or_init(::core::default::Default::default).lock().unwrap_or_else(|e| e.into_inner());
+            let cached = cache().get(&::core::any::TypeId::of::<Self>()).map(|cached| cached.0);
+            // The lock is released while building, as fields may use the datatype of another instantiation
+            match cached {
+                Some(raw) => raw,
+                None => {
+                    // A datatype built concurrently by another thread is freed when dropped
+                    let built = build();
+                    cache().entry(::core::any::TypeId::of::<Self>()).or_insert_with(|| __MpiCached::new(built)).0
+                }
+            }
+        }
+    };
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    quote! {
+        unsafe impl #impl_generics mpi::datatype::traits::Equivalence for #name #ty_generics #where_clause {
+            type Out = mpi::datatype::DatatypeRef<'static>;
+            fn equivalent_datatype() -> Self::Out {
+                // Built outside the scope of the cache, whose items would shadow the types of the fields
+                let build = || -> mpi::datatype::UserDatatype { #datatype };
+                let raw = {
+                    /// Committed datatype that is never freed
+                    struct __MpiCached(mpi::ffi::MPI_Datatype);
+                    unsafe impl Send for __MpiCached {}
+                    unsafe impl Sync for __MpiCached {}
+                    impl __MpiCached {
+                        fn new(datatype: mpi::datatype::UserDatatype) -> Self {
+                            let raw = mpi::raw::AsRaw::as_raw(&datatype);
+                            ::core::mem::forget(datatype);
+                            __MpiCached(raw)
+                        }
+                    }
+
+                    #lookup
+                };
+                unsafe { mpi::datatype::DatatypeRef::from_raw(raw) }
+            }
+        }
+    }
+}
+
+/// Add the where predicates required by the Equivalence implementation
+///
+/// Like serde, the bounds are inferred: each leaf type of the typemap using a type parameter must
+/// implement the Equivalence trait. `#[mpi(bound = "...")]` replaces the inferred bounds.
+fn add_trait_bounds(generics: &Generics, attrs: &ContainerAttrs, leaf_types: &[syn::Type]) -> Generics {
+    let mut generics = generics.clone();
+    let predicates: Vec<syn::WherePredicate> = match attrs.bound {
+        Some(ref bound) => bound.clone(),
+        None => {
+            let type_params = generics.type_params().map(|param| param.ident.clone()).collect::<Vec<_>>();
+            leaf_types.iter()
+                .filter(|t| uses_any_ident(quote!(#t), &type_params))
+                .map(|t| syn::parse_quote!(#t: mpi::datatype::Equivalence))
+                .collect()
+        }
+    };
+    if !predicates.is_empty() {
+        generics.make_where_clause().predicates.extend(predicates);
+    }
+    generics
+}
+
+/// Check whether some tokens contain one of the identifiers
+fn uses_any_ident(tokens: TokenStream, idents: &[Ident]) -> bool {
+    tokens.into_iter().any(|token| match token {
+        proc_macro2::TokenTree::Ident(ref ident) => idents.contains(ident),
+        proc_macro2::TokenTree::Group(ref group) => uses_any_ident(group.stream(), idents),
+        _ => false,
+    })
+}
+
+/// Implement the Equivalence trait for an enum with an integer representation
+///
+/// Receiving directly into the enum would materialise an invalid value if the sender does not
+/// send a valid discriminant, so helpers checking the discriminant are generated too.
+fn impl_enum_equivalence(input: &DeriveInput, data: &DataEnum) -> Result<TokenStream, Vec<syn::Error>> {
+    let (repr, attrs) = match (enum_repr(&input.ident, &input.attrs), ContainerAttrs::parse(&input.attrs)) {
+        (Ok(repr), Ok(attrs)) => (repr, attrs),
+        (repr, attrs) => return Err(repr.err().into_iter().chain(attrs.err()).collect()),
+    };
+    check_struct_only_attrs(input, &attrs)?;
+    if let Some(ref active) = attrs.active {
+        return Err(vec![syn::Error::new(active.span(), "`active` can only be used on unions")]);
+    }
+
+    let equivalence = if data.variants.iter().all(|v| matches!(v.fields, Fields::Unit)) {
+        if attrs.cached || attrs.bound.is_some() {
+            return Err(vec![syn::Error::new(input.ident.span(),
+                "`cached` and `bound` are not supported on field-less enums, which use the datatype of their integer representation")]);
+        }
+        impl_fieldless_enum_equivalence(input, data, &repr)
+    } else {
+        impl_data_enum_equivalence(input, &attrs, data, &repr)?
+    };
+    let fingerprint = impl_enum_fingerprint(input, data, &repr);
+    Ok(quote! {
+        #equivalence
+        #fingerprint
+    })
+}
+
+/// Generate the constants `__MPI_DISCRIMINANT_<i>` holding the discriminant of each variant
+///
+/// Discriminants are explicit, or the previous one plus one, as for field-less enums. The names are
+/// reserved, as the constants are in scope of the expressions of explicit discriminants.
+fn enum_discriminants(data: &DataEnum, repr: &EnumRepr) -> Vec<TokenStream> {
+    let mut previous: Option<Ident> = None;
+    data.variants.iter().enumerate().map(|(i, v)| {
+        let discriminant = Ident::new(&format!("__MPI_DISCRIMINANT_{}", i), v.span());
+        let value = match (&v.discriminant, &previous) {
+            (Some((_, expr)), _) => quote!(#expr),
+            (None, Some(previous)) => quote!(#previous + 1),
+            (None, None) => quote!(0),
+        };
+        previous = Some(discriminant.clone());
+        quote_spanned! { v.span() => const #discriminant: #repr = #value; }
+    }).collect()
+}
+
+/// Generate the fingerprint of an enum, describing each variant with its discriminant, then its fields
+fn impl_enum_fingerprint(input: &DeriveInput, data: &DataEnum, repr: &EnumRepr) -> TokenStream {
+    let discriminants = enum_discriminants(data, repr);
+    let repr_name = quote!(#repr).to_string();
+    let mut entries = Vec::new();
+    let mut field_types = Vec::new();
+    for (i, v) in data.variants.iter().enumerate() {
+        let variant = v.ident.to_string();
+        let discriminant = Ident::new(&format!("__MPI_DISCRIMINANT_{}", i), v.span());
+        entries.push(quote!((#variant, #discriminant as usize, 0, #repr_name, 0)));
+        for (index, f) in v.fields.iter().enumerate() {
+            let field_name = format!("{}.{}", variant, field_member(f, index));
+            let field_type = &f.ty;
+            let field_type_name = type_name(field_type);
+            entries.push(quote!((#field_name, 0, ::core::mem::size_of::<#field_type>(), #field_type_name, 1)));
+            field_types.push(field_type);
+        }
+    }
+    impl_fingerprint(input, quote!({
+        #(#discriminants)*
+        &[#(#entries),*]
+    }), &field_types)
+}
+
+/// Implement the Equivalence trait for a field-less enum, using the datatype of its integer representation
+fn impl_fieldless_enum_equivalence(input: &DeriveInput, data: &DataEnum, repr: &EnumRepr) -> TokenStream {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let check_repr = check_enum_repr(data, repr);
+
+    let checks = data.variants.iter().map(|v| {
+        let variant = &v.ident;
+        quote_spanned! { v.span() =>
+            if discriminant == #name::#variant as #repr {
+                return Some(#name::#variant);
+            }
+        }
+    });
+
+    quote! {
+        unsafe impl #impl_generics mpi::datatype::traits::Equivalence for #name #ty_generics #where_clause {
+            type Out = <#repr as mpi::datatype::traits::Equivalence>::Out;
+            fn equivalent_datatype() -> Self::Out {
+                #check_repr
+                <#repr as mpi::datatype::traits::Equivalence>::equivalent_datatype()
+            }
+        }
+
+        impl #impl_generics #name #ty_generics #where_clause {
+            /// Convert a discriminant received as its integer representation, `None` if no variant matches
+            pub fn from_discriminant(discriminant: #repr) -> Option<Self> {
+                #(#checks)*
+                None
+            }
+
+            /// Receive a single value, checking its discriminant before materialising it
+            pub fn receive_checked(source: &impl mpi::point_to_point::Source) -> (Option<Self>, mpi::point_to_point::Status) {
+                let (discriminant, status) = mpi::point_to_point::Source::receive::<#repr>(source);
+                (Self::from_discriminant(discriminant), status)
+            }
+        }
+    }
+}
+
+/// Implement the Equivalence trait for an enum carrying data
+///
+/// RFC 2195 defines the layout of such enums as a union of `#[repr(C)]` structs (or a `#[repr(C)]`
+/// struct of the tag and a union) which all start with the tag. The datatype is made of the tag,
+/// followed by the remaining bytes of the enum. Sending each variant's typemap instead would
+/// describe overlapping entries, which MPI forbids for receive buffers. The fields of the variants
+/// must then be plain data, as the fields of `#[mpi(bytes)]` structs.
+fn impl_data_enum_equivalence(input: &DeriveInput, attrs: &ContainerAttrs, data: &DataEnum, repr: &EnumRepr) -> Result<TokenStream, Vec<syn::Error>> {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let mut errors = Vec::new();
+    let mut checks = Vec::new();
+    for v in data.variants.iter() {
+        if let Some(attr) = v.attrs.iter().find(|attr| attr.path.is_ident("mpi")) {
+            errors.push(syn::Error::new_spanned(attr, "Attributes cannot be used on variants, which are sent as bytes"));
+        }
+        match FieldAttrs::parse_fields(&v.fields).and_then(|fields| check_byte_fields(&input.generics, &fields)) {
+            Ok(variant_checks) => checks.push(variant_checks),
+            Err(variant_errors) => errors.extend(variant_errors),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let check_repr = check_enum_repr(data, repr);
+
+    let discriminants = enum_discriminants(data, repr);
+    let names = (0..data.variants.len()).map(|i| Ident::new(&format!("__MPI_DISCRIMINANT_{}", i), name.span()));
+
+    let equivalence = impl_user_datatype_equivalence(input, attrs, quote! {{
+        #(#checks)*
+        #check_repr
+
+        let tag_size = ::std::mem::size_of::<#repr>();
+        mpi::datatype::UserDatatype::structured(
+            2,
+            &[1, (::std::mem::size_of::<Self>() - tag_size) as mpi::Count],
+            &[0, tag_size as mpi::Address],
+            &[
+                &<#repr as mpi::datatype::Equivalence>::equivalent_datatype(),
+                &<u8 as mpi::datatype::Equivalence>::equivalent_datatype(),
+            ],
+        )
+    }}, &[]);
+
+    Ok(quote! {
+        #equivalence
+
+        impl #impl_generics #name #ty_generics #where_clause {
+            /// Receive a single value, checking its discriminant before materialising it
+            ///
+            /// The payload is trusted to be valid for the variant, as when receiving a struct.
+            pub fn receive_checked(source: &impl mpi::point_to_point::Source) -> (Option<Self>, mpi::point_to_point::Status) {
+                #(#discriminants)*
+
+                let mut value = ::std::mem::MaybeUninit::<Self>::zeroed();
+                let status = {
+                    // The zeroed value is a valid byte buffer to receive into
+                    let bytes = unsafe {
+                        ::std::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, ::std::mem::size_of::<Self>())
+                    };
+                    let datatype = <Self as mpi::datatype::Equivalence>::equivalent_datatype();
+                    let mut view = unsafe { mpi::datatype::MutView::with_count_and_datatype(bytes, 1, &datatype) };
+                    mpi::point_to_point::Source::receive_into(source, &mut view)
+                };
+                // The tag is always at the start of the enum
+                let tag = unsafe { ::std::ptr::read(value.as_ptr() as *const #repr) };
+                if [#(#names),*].contains(&tag) {
+                    (Some(unsafe { value.assume_init() }), status)
+                } else {
+                    (None, status)
                 }
+            }
+        }
+    })
+}
+
+/// Implement the Equivalence trait for a `#[repr(C)]` union
+///
+/// The active field is unknown, so the union is sent as bytes, unless `#[mpi(active = field)]`
+/// guarantees which field is active: that field is then sent with its typemap, in a datatype
+/// whose extent is the size of the union.
+fn impl_union_equivalence(input: &DeriveInput, data: &DataUnion) -> Result<TokenStream, Vec<syn::Error>> {
+    let repr = if has_repr(&input.attrs, "C") {
+        Ok(())
+    } else {
+        Err(syn::Error::new(data.union_token.span(),
+            "Deriving Equivalence for a union requires `#[repr(C)]`, as the layout of other unions is unspecified"))
+    };
+    let (attrs, fields) = match (repr, ContainerAttrs::parse(&input.attrs), FieldAttrs::parse_fields(&data.fields.named)) {
+        (Ok(()), Ok(attrs), Ok(fields)) => (attrs, fields),
+        (repr, attrs, fields) => return Err(repr.err().into_iter().chain(attrs.err()).chain(fields.err().into_iter().flatten()).collect()),
+    };
+    check_struct_only_attrs(input, &attrs)?;
+    let fingerprint = impl_union_fingerprint(input, data, attrs.active.as_ref());
+
+    let active = match attrs.active {
+        Some(ref active) => active,
+        None => {
+            let checks = check_byte_fields(&input.generics, &fields)?;
+            // Unions may have fields of non-`Copy` types wrapped in `ManuallyDrop`, whose bytes cannot be copied
+            let mut input = input.clone();
+            let predicates = &mut input.generics.make_where_clause().predicates;
+            for f in data.fields.named.iter() {
+                let ty = &f.ty;
+                predicates.push(syn::parse_quote!(#ty: ::core::marker::Copy));
+            }
+            let datatype = bytes_datatype();
+            let equivalence = impl_user_datatype_equivalence(&input, &attrs, quote!({ #checks #datatype }), &[]);
+            let plain_data = impl_plain_data(&input);
+            return Ok(quote! {
+                #equivalence
+                #plain_data
+                #fingerprint
             });
-            let types = tuple.elems.iter().map(|t| {
-                get_datatype(t)
+        }
+    };
+
+    // The other fields are not sent
+    let mut errors = Vec::new();
+    let mut active_field = None;
+    for (f, attrs) in fields {
+        if f.ident.as_ref() == Some(active) {
+            match attrs {
+                FieldAttrs { reduce: Some(ref op), .. } => errors.push(syn::Error::new(op.span(), "Reductions are not generated for unions")),
+                FieldAttrs { since: Some(ref since), .. } => errors.push(syn::Error::new(since.span(), "Versions are not supported on unions")),
+                _ => {}
+            }
+            active_field = Some((f, attrs));
+        } else if !attrs.is_empty() {
+            errors.push(syn::Error::new_spanned(f, "Field attributes can only be used on the active field of a union"));
+        }
+    }
+    let active_field = match active_field {
+        Some(active_field) => active_field,
+        None => {
+            errors.push(syn::Error::new(active.span(), format!("The union has no field `{}`", active)));
+            return Err(errors);
+        }
+    };
+
+    // The typemap of the active field is the one of a structure with this field alone
+    match create_struct_datatype(std::slice::from_ref(&active_field), None) {
+        Ok((datatype, leaf_types)) if errors.is_empty() => {
+            let equivalence = impl_user_datatype_equivalence(input, &attrs, datatype, &leaf_types);
+            Ok(quote! {
+                #equivalence
+                #fingerprint
+            })
+        }
+        result => Err(errors.into_iter().chain(result.err().into_iter().flatten()).collect()),
+    }
+}
+
+/// Generate the fingerprint of a union, describing its fields, of which only the active one is sent if any
+fn impl_union_fingerprint(input: &DeriveInput, data: &DataUnion, active: Option<&Ident>) -> TokenStream {
+    let entries = data.fields.named.iter().map(|f| {
+        let field_name = f.ident.as_ref().map_or_else(String::new, Ident::to_string);
+        let field_type = &f.ty;
+        let field_type_name = type_name(field_type);
+        let count: usize = match active {
+            Some(active) if f.ident.as_ref() != Some(active) => 0,
+            _ => 1,
+        };
+        quote!((#field_name, 0, ::core::mem::size_of::<#field_type>(), #field_type_name, #count))
+    });
+    let field_types = data.fields.named.iter().map(|f| &f.ty).collect::<Vec<_>>();
+    impl_fingerprint(input, quote!(&[#(#entries),*]), &field_types)
+}
+
+/// Representation of the discriminant of an enum
+enum EnumRepr {
+    /// Primitive representation, which may be combined with `C`
+    Int(Ident),
+    /// `#[repr(C)]` alone, whose discriminant is a C `int`
+    C(Ident),
+}
+
+impl quote::ToTokens for EnumRepr {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        match self {
+            EnumRepr::Int(ident) => ident.to_tokens(tokens),
+            EnumRepr::C(ident) => tokens.extend(quote_spanned!(ident.span()=> ::std::os::raw::c_int)),
+        }
+    }
+}
+
+/// Find the representation of an enum, whose discriminant must have an equivalent MPI datatype
+fn enum_repr(enum_name: &Ident, attrs: &[Attribute]) -> Result<EnumRepr, syn::Error> {
+    let mut repr_c = None;
+    for attr in attrs {
+        let list = match attr.interpret_meta() {
+            Some(syn::Meta::List(ref list)) if list.ident == "repr" => list.nested.clone(),
+            _ => continue,
+        };
+        for nested in list {
+            if let syn::NestedMeta::Meta(syn::Meta::Word(ident)) = nested {
+                match ident.to_string().as_str() {
+                    "u8" | "u16" | "u32" | "u64" | "usize" | "i8" | "i16" | "i32" | "i64" | "isize" => return Ok(EnumRepr::Int(ident)),
+                    "u128" | "i128" => return Err(syn::Error::new(ident.span(),
+                        format!("`#[repr({})]` has no equivalent MPI datatype", ident))),
+                    "C" => repr_c = Some(ident),
+                    _ => {}
+                }
+            }
+        }
+    }
+    match repr_c {
+        Some(ident) => Ok(EnumRepr::C(ident)),
+        None => Err(syn::Error::new(enum_name.span(),
+            "Deriving Equivalence for an enum requires an integer or `C` representation such as `#[repr(u8)]`")),
+    }
+}
+
+/// Generate a check that the discriminant of a `#[repr(C)]` enum is a C `int`
+///
+/// The discriminant is larger when its values do not fit, so the check uses a field-less enum
+/// with the same discriminants, which is the type of the tag of enums carrying data.
+fn check_enum_repr(data: &DataEnum, repr: &EnumRepr) -> TokenStream {
+    let ident = match repr {
+        EnumRepr::C(ident) => ident,
+        EnumRepr::Int(_) => return TokenStream::new(),
+    };
+    let variants = data.variants.iter().map(|v| {
+        let variant = &v.ident;
+        match v.discriminant {
+            Some((_, ref expr)) => quote!(#variant = #expr),
+            None => quote!(#variant),
+        }
+    });
+    // In its own scope, so that the enum does not shadow a type of the fields
+    quote_spanned! {ident.span()=>
+        const _: () = {
+            #[allow(dead_code)]
+            #[repr(C)]
+            enum Tag {
+                #(#variants),*
+            }
+            assert!(
+                ::std::mem::size_of::<Tag>() == ::std::mem::size_of::<::std::os::raw::c_int>(),
+                "The discriminant of this `#[repr(C)]` enum does not fit a C `int`, use an integer representation"
+            );
+        };
+    }
+}
+
+/// Create a MPI Datatype for a structure
+///
+/// Zero-sized fields and fields marked `#[mpi(skip)]` are left out of the typemap, so unit
+/// structs get an empty datatype.
+///
+/// Tuples are flattened, and runs of the same datatype that are contiguous in memory are merged
+/// into blocks. Arrays whose elements are not leaves are an entry of the datatype of the element.
+/// A structure made of a single run, without padding, is a contiguous datatype.
+/// The leaf types of the typemap are returned to infer the bounds on type parameters.
+///
+/// With `view`, only the given fields are part of the typemap.
+fn create_struct_datatype(fields: &[(&syn::Field, FieldAttrs)], view: Option<&[syn::Member]>) -> Result<(TokenStream, Vec<syn::Type>), Vec<syn::Error>> {
+    let (entries, leaf_types) = struct_typemap(fields, view, None, build_datatype(quote!(Self)))?;
+    let datatype = quote! {{
+        // Entries are (offset, count, size of an element if it is the extent of its datatype, datatype)
+        let typemap: &mut Vec<(usize, usize, Option<usize>, mpi::ffi::MPI_Datatype)> = &mut Vec::new();
+        #entries
+    }};
+    Ok((datatype, leaf_types))
+}
+
+/// Create the datatype of some of the fields of a structure, for the stages following its datatype
+///
+/// The errors of the fields are those of the datatype of the structure, which reports them, so an
+/// empty expression is returned instead.
+fn subset_datatype(fields: &[(&syn::Field, FieldAttrs)], members: &[syn::Member]) -> (TokenStream, Vec<syn::Type>) {
+    create_struct_datatype(fields, Some(members)).unwrap_or_default()
+}
+
+/// Generate code building the datatype of `type_name` from the entries of `typemap`
+///
+/// The entries are merged into blocks, and the datatype is resized to the size of the type.
+fn build_datatype(type_name: TokenStream) -> TokenStream {
+    let merge_blocks: TokenStream = include_str!("merge_blocks.rs").parse().expect("merge_blocks.rs is valid Rust");
+    let structured = resized_datatype(type_name.clone(), quote! {
+        mpi::datatype::UserDatatype::structured(
+            count(blocks.len()),
+            &blocks.iter().map(|block| count(block.1)).collect::<Vec<_>>(),
+            &blocks.iter().map(|block| block.0 as mpi::Address).collect::<Vec<_>>(),
+            &datatypes.iter().map(|datatype| datatype as &dyn mpi::datatype::Datatype<Raw = mpi::ffi::MPI_Datatype>).collect::<Vec<_>>(),
+        )
+    });
+    quote! {
+        // Array lengths, including const generic ones, are usize: check that they fit the MPI count type
+        // once merged, as the large-count constructors of MPI 4 are not available
+        let count = |length: usize| -> mpi::Count {
+            ::core::convert::TryFrom::try_from(length).unwrap_or_else(|_| panic!(
+                "The datatype of {} has a block of {} elements, which exceeds the range of the MPI count type",
+                ::core::any::type_name::<#type_name>(),
+                length,
+            ))
+        };
+
+        // In its own scope, so that the function does not shadow the functions of the fields
+        let blocks = {
+            #merge_blocks
+            merge_blocks(typemap)
+        };
+
+        match blocks[..] {
+            [(0, length, Some(size), datatype)] if length * size == ::core::mem::size_of::<#type_name>() => {
+                mpi::datatype::UserDatatype::contiguous(
+                    count(length),
+                    &unsafe { mpi::datatype::DatatypeRef::from_raw(datatype) },
+                )
+            }
+            _ => {
+                let datatypes = blocks.iter()
+                    .map(|block| unsafe { mpi::datatype::DatatypeRef::from_raw(block.3) })
+                    .collect::<Vec<_>>();
+                #structured
+            }
+        }
+    }
+}
+
+/// Generate code pushing the entries of the fields of a structure to `typemap`, then evaluating `then`
+///
+/// The entries are at `base` plus the offset of their field. The fields of `#[mpi(flatten)]` fields
+/// are pushed by the `__mpi_flatten` function of their type, which calls `then` while their datatypes
+/// are alive, so `then` is nested in a closure for each flattened field.
+fn struct_typemap(fields: &[(&syn::Field, FieldAttrs)], view: Option<&[syn::Member]>, base: Option<&TokenStream>, then: TokenStream) -> Result<(TokenStream, Vec<syn::Type>), Vec<syn::Error>> {
+    let mut typemap = Typemap::default();
+    let mut entries = Vec::new();
+    let mut flattened = Vec::new();
+    let mut layout_checks = Vec::new();
+    for (i, (f, attrs)) in fields.iter().enumerate() {
+        if attrs.skip || is_zero_sized(&f.ty) {
+            continue;
+        }
+        if let Some(view) = view {
+            let member = field_member(f, i);
+            if view.iter().all(|m| quote!(#m).to_string() != member) {
+                continue;
+            }
+        }
+        let offset = match f.ident {
+            Some(ref field_name) => offset_of_field(quote!(Self), quote!(#field_name), f.span()),
+            None => {
+                let field_index = Index::from(i);
+                offset_of_field(quote!(Self), quote!(#field_index), f.span())
+            }
+        };
+        let offset = match base {
+            Some(base) => quote!(#base + #offset),
+            None => offset,
+        };
+        if attrs.flatten {
+            typemap.flattened(&f.ty);
+            flattened.push((&f.ty, offset));
+            continue;
+        }
+        entries.push(match (&attrs.datatype, &attrs.as_type) {
+            (Some(function), _) => typemap.custom_entry(function, offset),
+            (None, Some(as_type)) => {
+                layout_checks.push(check_same_size(&f.ty, as_type, AS_SIZE_MESSAGE));
+                typemap.entries(as_type, offset)
+            }
+            (None, None) => typemap.entries(&f.ty, offset),
+        });
+    }
+    let datatypes = typemap.bindings();
+    let then = flattened.into_iter().rev().fold(then, |then, (t, offset)| quote_spanned! { t.span() =>
+        <#t>::__mpi_flatten(#offset, typemap, |typemap| {
+            #then
+        })
+    });
+    let typemap_entries = quote! {
+        #(#layout_checks)*
+        #datatypes
+        #(#entries)*
+        #then
+    };
+    if typemap.errors.is_empty() {
+        Ok((typemap_entries, typemap.leaf_types))
+    } else {
+        Err(typemap.errors)
+    }
+}
+
+/// Create a MPI Datatype of `size_of::<Self>()` bytes for a structure marked `#[mpi(bytes)]`
+///
+/// Padding bytes are sent too, as for the payloads of enums and unions sent as bytes: MPI copies
+/// them without interpreting them, and receivers overwrite padding of their own.
+fn create_bytes_datatype(generics: &syn::Generics, fields: &[(&syn::Field, FieldAttrs)]) -> Result<TokenStream, Vec<syn::Error>> {
+    let checks = check_byte_fields(generics, fields)?;
+    let datatype = bytes_datatype();
+    Ok(quote! {{
+        #checks
+        #datatype
+    }})
+}
+
+/// Check that fields sent as bytes have no mpi attributes and are plain data
+///
+/// Returns the statements checking at compile time the types that are plain data when they are
+/// what they are named after: primitive numbers, and the types whose Equivalence implementation
+/// is derived with `#[mpi(bytes)]`, which have the hidden `__MPI_PLAIN_DATA` constant.
+fn check_byte_fields(generics: &syn::Generics, fields: &[(&syn::Field, FieldAttrs)]) -> Result<TokenStream, Vec<syn::Error>> {
+    let type_params = generics.type_params().map(|param| &param.ident).collect::<Vec<_>>();
+    let mut errors = Vec::new();
+    let mut checks = Vec::new();
+    for (f, attrs) in fields {
+        if !attrs.is_empty() {
+            errors.push(syn::Error::new_spanned(f, "Field attributes cannot be used on fields sent as bytes"));
+        }
+        if !is_zero_sized(&f.ty) {
+            if let Err(error) = check_plain_data(&f.ty, &type_params, &mut checks) {
+                errors.push(error);
+            }
+        }
+    }
+    if errors.is_empty() {
+        Ok(quote! { #(#checks)* })
+    } else {
+        Err(errors)
+    }
+}
+
+/// Check that a type sent as bytes is plain data, whose every bit pattern is a valid value
+fn check_plain_data(t: &syn::Type, type_params: &[&Ident], checks: &mut Vec<TokenStream>) -> Result<(), syn::Error> {
+    match ungroup(t) {
+        syn::Type::Array(array) => check_plain_data(&array.elem, type_params, checks),
+        syn::Type::Tuple(tuple) => tuple.elems.iter().try_for_each(|t| check_plain_data(t, type_params, checks)),
+        syn::Type::Path(path) if path.qself.is_none() => {
+            let segment = path.path.segments.last().map(|segment| segment.into_value());
+            match segment {
+                Some(segment) if path.path.segments.len() == 1 && path.path.leading_colon.is_none() && segment.arguments.is_empty() => {
+                    let ident = &segment.ident;
+                    if type_params.contains(&ident) {
+                        return Err(syn::Error::new_spanned(t,
+                            "Type parameters cannot be sent as bytes, as they may not be plain data"));
+                    }
+                    if ident == "bool" || ident == "char" {
+                        return Err(syn::Error::new_spanned(t,
+                            "`bool` and `char` cannot be sent as bytes, as some of their bit patterns are invalid"));
+                    }
+                    if PLAIN_PRIMITIVES.iter().any(|primitive| ident == primitive) {
+                        // Fails if the name is shadowed by another type
+                        checks.push(quote_spanned! {t.span()=>
+                            let _: fn(#t) -> ::core::primitive::#ident = |value| value;
+                        });
+                        return Ok(());
+                    }
+                }
+                _ => {}
+            }
+            checks.push(quote_spanned! {t.span()=>
+                #[allow(clippy::let_unit_value)]
+                let () = <#t>::__MPI_PLAIN_DATA;
             });
-            quote_spanned! { tuple.span() =>
-                  &mpi::datatype::UserDatatype::structured(
-                      #len,
-                      &[1; #len as usize],
-                      &[#(#offsets,)*],
-                      &[#(#types,)*])
-             }
-        }
-        // Real types must implement the Equivalent traits
-        syn::Type::Path(path) => {
-            quote_spanned! { path.span() =>
-                &<#path as mpi::datatype::Equivalence>::equivalent_datatype()
+            Ok(())
+        }
+        t @ syn::Type::Reference(_) | t @ syn::Type::Ptr(_) | t @ syn::Type::BareFn(_) => Err(syn::Error::new_spanned(t,
+            "Types sent as bytes cannot hold references or pointers, as addresses are meaningless in other processes")),
+        t => Err(syn::Error::new_spanned(t,
+            "Only primitive numbers, arrays, tuples and `#[mpi(bytes)]` types can be sent as bytes")),
+    }
+}
+
+/// Primitive types whose every bit pattern is a valid value
+const PLAIN_PRIMITIVES: &[&str] = &[
+    "u8", "u16", "u32", "u64", "u128", "usize", "i8", "i16", "i32", "i64", "i128", "isize", "f32", "f64",
+];
+
+/// Generate the hidden constant marking a type sent as bytes as plain data
+///
+/// Structs with a field of the type can then be sent as bytes too.
+fn impl_plain_data(input: &DeriveInput) -> TokenStream {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    quote! {
+        impl #impl_generics #name #ty_generics #where_clause {
+            #[doc(hidden)]
+            pub const __MPI_PLAIN_DATA: () = ();
+        }
+    }
+}
+
+/// Generate the expression creating a MPI Datatype of `size_of::<Self>()` bytes
+///
+/// rsmpi has no `MPI_BYTE`, so the bytes are `u8`s, which is the same on the homogeneous systems
+/// types sent as bytes are meant for.
+fn bytes_datatype() -> TokenStream {
+    quote! {
+        mpi::datatype::UserDatatype::contiguous(
+            ::core::convert::TryFrom::try_from(::core::mem::size_of::<Self>())
+                .expect("The size of the type exceeds the range of the MPI count type"),
+            &<u8 as mpi::datatype::Equivalence>::equivalent_datatype(),
+        )
+    }
+}
+
+/// Flattened typemap of a structure
+///
+/// The datatypes of the leaves of the typemap are created once, and bound to `datatype_<index>`
+/// so that they outlive the creation of the structured datatype.
+#[derive(Default)]
+struct Typemap {
+    /// Key identifying the datatype and expression creating it
+    datatypes: Vec<(String, TokenStream)>,
+    /// Types implementing the Equivalence trait used by the entries
+    leaf_types: Vec<syn::Type>,
+    /// Errors for the fields that cannot be sent
+    errors: Vec<syn::Error>,
+}
+
+impl Typemap {
+    fn datatype_name(index: usize) -> Ident {
+        Ident::new(&format!("datatype_{}", index), proc_macro2::Span::call_site())
+    }
+
+    /// Get the name of a datatype, creating it if it is not used by a previous entry
+    fn datatype(&mut self, key: String, datatype: TokenStream) -> Ident {
+        let index = match self.datatypes.iter().position(|(k, _)| *k == key) {
+            Some(index) => index,
+            None => {
+                self.datatypes.push((key, datatype));
+                self.datatypes.len() - 1
+            }
+        };
+        Self::datatype_name(index)
+    }
+
+    /// Generate code pushing an entry for the datatype returned by `function` at `offset`
+    ///
+    /// Its extent is unknown, so it is never merged with other entries.
+    fn custom_entry(&mut self, function: &syn::Path, offset: TokenStream) -> TokenStream {
+        let datatype = self.datatype(format!("fn {}", quote!(#function)), quote_spanned! { function.span() => #function() });
+        quote! {
+            typemap.push((#offset, 1, None, mpi::raw::AsRaw::as_raw(&#datatype)));
+        }
+    }
+
+    /// Generate code pushing the entries of a value of type `t` at `offset` to the typemap
+    ///
+    /// Types implementing the Equivalence trait are leaves, arrays and tuples of those types are flattened.
+    fn entries(&mut self, t: &syn::Type, offset: TokenStream) -> TokenStream {
+        let t = ungroup(t);
+        if is_zero_sized(t) {
+            return TokenStream::new();
+        }
+        match t {
+            // Arrays are a single entry, of the datatype of their elements if they are not leaves
+            syn::Type::Array(array) => {
+                let len = &array.len;
+                let elem = ungroup(&array.elem);
+                match elem {
+                    syn::Type::Path(_) | syn::Type::Macro(_) => self.leaf_entry(elem, offset, quote!(#len)),
+                    _ if is_zero_sized(elem) => TokenStream::new(),
+                    _ => {
+                        let datatype = self.element_datatype(elem);
+                        quote_spanned! { array.span() =>
+                            typemap.push((#offset, #len, mergeable_size(mpi::raw::AsRaw::as_raw(&#datatype), ::core::mem::size_of::<#elem>()), mpi::raw::AsRaw::as_raw(&#datatype)));
+                        }
+                    }
+                }
+            }
+            // Recursion for tuples
+            syn::Type::Tuple(tuple) => {
+                let elem_entries = tuple.elems.iter().enumerate().map(|(i, t)| {
+                    let field_index = Index::from(i);
+                    let elem_offset = offset_of_field(quote!(#tuple), quote!(#field_index), t.span());
+                    self.entries(t, quote!(#offset + #elem_offset))
+                }).collect::<Vec<_>>();
+                quote! { #(#elem_entries)* }
+            }
+            // Real types, including qualified paths and types expanded from macros, must implement the Equivalent traits
+            syn::Type::Path(_) | syn::Type::Macro(_) => self.leaf_entry(t, offset, quote!(1)),
+            _ => {
+                self.errors.push(unsupported_type(t));
+                TokenStream::new()
+            }
+        }
+    }
+
+    /// Check the type of a `#[mpi(flatten)]` field, whose fields are pushed by its `__mpi_flatten` function
+    ///
+    /// The bounds of the flattened type are unknown, so its type arguments are required to implement
+    /// the Equivalence trait, like leaf types.
+    fn flattened(&mut self, t: &syn::Type) {
+        match ungroup(t) {
+            syn::Type::Path(path) if path.qself.is_none() => {
+                let arguments = path.path.segments.iter().flat_map(|segment| match segment.arguments {
+                    syn::PathArguments::AngleBracketed(ref arguments) => arguments.args.iter().collect(),
+                    _ => Vec::new(),
+                });
+                for argument in arguments {
+                    if let syn::GenericArgument::Type(ref t) = argument {
+                        self.leaf_types.push(t.clone());
+                    }
+                }
+            }
+            t => self.errors.push(syn::Error::new_spanned(t, "`flatten` requires a struct deriving `Equivalence`")),
+        }
+    }
+
+    /// Generate code pushing an entry of `count` values of a type implementing the Equivalence trait
+    fn leaf_entry(&mut self, t: &syn::Type, offset: TokenStream, count: TokenStream) -> TokenStream {
+        let key = quote!(#t).to_string();
+        if self.datatypes.iter().all(|(k, _)| *k != key) {
+            self.leaf_types.push(t.clone());
+        }
+        let datatype = self.datatype(key, quote_spanned! { t.span() =>
+            <#t as mpi::datatype::Equivalence>::equivalent_datatype()
+        });
+        quote! {
+            typemap.push((#offset, #count, mergeable_size(mpi::raw::AsRaw::as_raw(&#datatype), ::core::mem::size_of::<#t>()), mpi::raw::AsRaw::as_raw(&#datatype)));
+        }
+    }
+
+    /// Get the name of the datatype of the elements of an array that are not leaves, creating it if needed
+    ///
+    /// The element is built like a structure, from its entries at offset 0, so that the array is a
+    /// single entry rather than the entries of each of its elements.
+    fn element_datatype(&mut self, elem: &syn::Type) -> Ident {
+        let key = format!("[{}]", quote!(#elem));
+        if let Some(index) = self.datatypes.iter().position(|(k, _)| *k == key) {
+            return Self::datatype_name(index);
+        }
+        let mut element = Typemap::default();
+        let entries = element.entries(elem, quote!(0));
+        let datatypes = element.bindings();
+        let build = build_datatype(quote!(#elem));
+        for t in element.leaf_types {
+            if self.leaf_types.iter().all(|leaf| quote!(#leaf).to_string() != quote!(#t).to_string()) {
+                self.leaf_types.push(t);
+            }
+        }
+        self.errors.extend(element.errors);
+        self.datatype(key, quote! {{
+            let typemap: &mut Vec<(usize, usize, Option<usize>, mpi::ffi::MPI_Datatype)> = &mut Vec::new();
+            #datatypes
+            #entries
+            #build
+        }})
+    }
+
+    /// Generate the bindings of the datatypes of the entries, and the function checking their extents
+    ///
+    /// The size of an element is only used to merge entries when it is the extent of its datatype,
+    /// which the hand-written implementations of the Equivalence trait may not have.
+    fn bindings(&self) -> TokenStream {
+        let datatypes = self.datatypes.iter().map(|(_, datatype)| datatype);
+        let names = (0..self.datatypes.len()).map(Self::datatype_name);
+        let mergeable_size = if self.datatypes.iter().any(|(key, _)| !key.starts_with("fn ")) {
+            quote! {
+                let mergeable_size = |datatype: mpi::ffi::MPI_Datatype, size: usize| -> Option<usize> {
+                    let (mut lb, mut extent) = (0, 0);
+                    unsafe {
+                        mpi::ffi::MPI_Type_get_extent(datatype, &mut lb, &mut extent);
+                    }
+                    Some(size).filter(|&size| lb == 0 && extent == size as mpi::Address)
+                };
             }
+        } else {
+            TokenStream::new()
+        };
+        quote! {
+            #(let #names = #datatypes;)*
+            #mergeable_size
         }
-        //_ => unimplemented!("Unimplemented for type: {:?}", t)
-        _ => unimplemented!()
     }
 }
 
-/// Generate code to calculate the offset of a field in a structure
+/// Error explaining why a value of type `t` cannot be sent, and what to use instead
+fn unsupported_type(t: &syn::Type) -> syn::Error {
+    let message = match t {
+        syn::Type::Reference(_) | syn::Type::Ptr(_) =>
+            "References and pointers cannot be sent, as addresses are meaningless in other processes; \
+             send the pointee in another message and use `#[mpi(skip)]` on this field",
+        syn::Type::BareFn(_) =>
+            "Function pointers cannot be sent, as addresses are meaningless in other processes; \
+             use `#[mpi(skip)]` on this field",
+        syn::Type::Slice(_) =>
+            "Slices cannot be sent in a datatype, as their length is unknown at compile time; use an array instead",
+        syn::Type::TraitObject(_) | syn::Type::ImplTrait(_) =>
+            "The layout of trait objects is unknown at compile time; \
+             use `#[mpi(as = Type)]` or `#[mpi(datatype = path::to::function)]` on this field",
+        syn::Type::Never(_) =>
+            "The never type has no values to send; use `#[mpi(skip)]` on this field",
+        _ =>
+            "The layout of this type is unknown to the derive macro; \
+             use `#[mpi(as = Type)]` or `#[mpi(datatype = path::to::function)]` on this field",
+    };
+    syn::Error::new_spanned(t, message)
+}
+
+const AS_SIZE_MESSAGE: &str = "The type given with `#[mpi(as = ...)]` does not have the size of the field";
+
+/// Generate a compile-time check that two types have the same size, failing with `message`
 ///
-/// This function is equivalent to the offset_of! macro:
-/// ```
-/// macro_rules! offset_of {
-///     ($T:ty, $field:tt) => {{
-///         let value: $T = unsafe { ::std::mem::uninitialized() };
+/// The check is an associated constant, so that it is evaluated for each instantiation of generic fields.
+/// The checking type has a reserved name, as the checked types are in its scope.
+fn check_same_size(field_type: &syn::Type, as_type: &syn::Type, message: &str) -> TokenStream {
+    quote_spanned! { as_type.span() =>
+        {
+            struct __MpiSameSize<A, B>(::core::marker::PhantomData<(A, B)>);
+            // Unused in views and checks that are never called
+            #[allow(dead_code)]
+            impl<A, B> __MpiSameSize<A, B> {
+                const CHECK: () = assert!(
+                    ::core::mem::size_of::<A>() == ::core::mem::size_of::<B>(),
+                    #message
+                );
+            }
+            #[allow(clippy::let_unit_value)]
+            let () = __MpiSameSize::<#field_type, #as_type>::CHECK;
+        }
+    }
+}
+
+/// Look through parentheses, and the invisible groups wrapping types substituted by `macro_rules!`
+fn ungroup(t: &syn::Type) -> &syn::Type {
+    match t {
+        syn::Type::Paren(paren) => ungroup(&paren.elem),
+        syn::Type::Group(group) => ungroup(&group.elem),
+        _ => t,
+    }
+}
+
+/// Recognise types that are always zero-sized, and may not implement the Equivalence trait
 ///
-///         let value_loc = &value as *const _ as usize;
-///         let field_loc = &value.$field as *const _ as usize;
+/// These are `()`, `[T; 0]`, `PhantomData<T>` and `PhantomPinned`.
+fn is_zero_sized(t: &syn::Type) -> bool {
+    match ungroup(t) {
+        syn::Type::Tuple(tuple) => tuple.elems.is_empty(),
+        syn::Type::Array(array) => match array.len {
+            syn::Expr::Lit(syn::ExprLit { lit: syn::Lit::Int(ref len), .. }) => len.value() == 0,
+            _ => false,
+        },
+        syn::Type::Path(path) => path.qself.is_none() && path.path.segments.last()
+            .is_some_and(|segment| {
+                let ident = &segment.value().ident;
+                ident == "PhantomData" || ident == "PhantomPinned"
+            }),
+        _ => false,
+    }
+}
+
+/// Generate code to set the lower bound of a datatype to 0 and its extent to the size of a type
 ///
-///         ::std::mem::forget(value);
+/// The extent of a structured datatype otherwise ends at its last field, which misaligns every
+/// element after the first in slices and arrays when the type has trailing padding.
+/// The resized datatype is wrapped in a contiguous datatype of one element to be owned by a `UserDatatype`.
+fn resized_datatype(type_name: TokenStream, datatype: TokenStream) -> TokenStream {
+    quote! {{
+        let datatype = #datatype;
+        let mut resized = ::core::mem::MaybeUninit::<mpi::ffi::MPI_Datatype>::uninit();
+        let mut resized = unsafe {
+            mpi::ffi::MPI_Type_create_resized(
+                mpi::raw::AsRaw::as_raw(&datatype),
+                0,
+                ::core::mem::size_of::<#type_name>() as mpi::Address,
+                resized.as_mut_ptr(),
+            );
+            resized.assume_init()
+        };
+        let datatype = mpi::datatype::UserDatatype::contiguous(1, &unsafe { mpi::datatype::DatatypeRef::from_raw(resized) });
+        unsafe {
+            mpi::ffi::MPI_Type_free(&mut resized);
+        }
+        datatype
+    }}
+}
+
+/// Generate code to calculate the offset of a field in a structure
 ///
-///         field_loc - value_loc
-///     }};
-/// }
-/// ```
+/// `core::mem::offset_of!` is a constant expression that never creates a value of the type nor a
+/// reference to the field, so it is sound for any field type and for `#[repr(packed)]` structures.
 fn offset_of_field(type_name: TokenStream, field_name: TokenStream, span: proc_macro2::Span) -> TokenStream {
     quote_spanned! {
-        span => {
-             let value: #type_name = unsafe { ::std::mem::uninitialized() };
+        span => ::core::mem::offset_of!(#type_name, #field_name)
+    }
+}
 
-             let value_loc = &value as *const _ as usize;
-             let field_loc = &value.#field_name as *const _ as usize;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse_quote;
 
-             ::std::mem::forget(value);
+    include!("merge_blocks.rs");
 
-             field_loc - value_loc
-        }
+    fn repr(input: DeriveInput) -> Result<String, String> {
+        enum_repr(&input.ident, &input.attrs).map(|repr| quote!(#repr).to_string()).map_err(|error| error.to_string())
+    }
+
+    #[test]
+    fn zero_sized() {
+        assert!(is_zero_sized(&parse_quote!(())));
+        assert!(is_zero_sized(&parse_quote!([f64; 0])));
+        assert!(is_zero_sized(&parse_quote!(PhantomData<T>)));
+        assert!(is_zero_sized(&parse_quote!(::core::marker::PhantomData<fn() -> T>)));
+        assert!(is_zero_sized(&parse_quote!(std::marker::PhantomPinned)));
+        assert!(!is_zero_sized(&parse_quote!((u8,))));
+        assert!(!is_zero_sized(&parse_quote!([u8; N])));
+        assert!(!is_zero_sized(&parse_quote!([(); 1 - 1])));
+        assert!(!is_zero_sized(&parse_quote!(<T as Trait>::PhantomData)));
+    }
+
+    #[test]
+    fn enum_reprs() {
+        assert_eq!(repr(parse_quote!(#[repr(u8)] enum E { A })), Ok("u8".to_string()));
+        assert_eq!(repr(parse_quote!(#[repr(C, i16)] enum E { A })), Ok("i16".to_string()));
+        assert_eq!(repr(parse_quote!(#[repr(C)] #[repr(u64)] enum E { A })), Ok("u64".to_string()));
+        assert_eq!(repr(parse_quote!(#[repr(C)] enum E { A })), Ok(":: std :: os :: raw :: c_int".to_string()));
+        assert_eq!(repr(parse_quote!(#[repr(i128)] enum E { A })), Err("`#[repr(i128)]` has no equivalent MPI datatype".to_string()));
+        let missing = repr(parse_quote!(#[repr(align(8))] enum E { A })).unwrap_err();
+        assert!(missing.starts_with("Deriving Equivalence for an enum requires"), "{}", missing);
+        assert!(repr(parse_quote!(#[derive(Clone)] enum E { A })).is_err());
+    }
+
+    #[test]
+    fn snake_case_names() {
+        assert_eq!(snake_case(&parse_quote!(Position)), "position");
+        assert_eq!(snake_case(&parse_quote!(PositionVelocity)), "position_velocity");
+        assert_eq!(snake_case(&parse_quote!(already_snake)), "already_snake");
+        assert_eq!(snake_case(&parse_quote!(r#Match)), "match");
+        assert_eq!(snake_case(&parse_quote!(X)), "x");
+    }
+
+    #[test]
+    fn merge_contiguous_entries() {
+        // Runs of the same datatype are merged, in the order of their offsets
+        let mut typemap = [(8, 2, Some(4), 'f'), (0, 1, Some(4), 'f'), (4, 1, Some(4), 'f'), (16, 1, Some(8), 'd')];
+        assert_eq!(merge_blocks(&mut typemap), [(0, 4, Some(4), 'f'), (16, 1, Some(8), 'd')]);
+    }
+
+    #[test]
+    fn merge_only_contiguous_entries() {
+        // Gaps, other datatypes and sizes, and datatypes whose extent is not their size break runs
+        let mut gap = [(0, 1, Some(4), 'f'), (8, 1, Some(4), 'f')];
+        assert_eq!(merge_blocks(&mut gap), gap);
+        let mut datatypes = [(0, 1, Some(4), 'f'), (4, 1, Some(4), 'i')];
+        assert_eq!(merge_blocks(&mut datatypes), datatypes);
+        let mut sizes = [(0, 1, Some(2), 'f'), (2, 1, Some(4), 'f')];
+        assert_eq!(merge_blocks(&mut sizes), sizes);
+        let mut extents = [(0, 1, None, 's'), (8, 1, None, 's')];
+        assert_eq!(merge_blocks(&mut extents), extents);
+        assert_eq!(merge_blocks::<char>(&mut []), []);
+    }
+
+    #[test]
+    fn sizes_of_primitives() {
+        let size = |t: syn::Type| primitives_size(&t).map(|size| size.to_string());
+        assert_eq!(size(parse_quote!(f64)), Some(":: core :: mem :: size_of :: < f64 > ( )".to_string()));
+        assert!(size(parse_quote!([(u8, u32); 2])).is_some());
+        assert!(size(parse_quote!(bool)).is_some());
+        assert_eq!(size(parse_quote!(T)), None);
+        assert_eq!(size(parse_quote!([Particle; 3])), None);
+        assert_eq!(size(parse_quote!((u8, T))), None);
+        assert_eq!(size(parse_quote!(std::primitive::u8)), None);
+    }
+
+    #[test]
+    fn type_names() {
+        let name = |t: syn::Type| type_name(&t);
+        assert_eq!(name(parse_quote!(std::primitive::u32)), "u32");
+        assert_eq!(name(parse_quote!(::core::primitive::u32)), "u32");
+        assert_eq!(name(parse_quote!(Wrapper<'a, std::primitive::u8>)), "Wrapper<u8>");
+        assert_eq!(name(parse_quote!([T; 2 * N])), "[T; 2*N]");
+        assert_eq!(name(parse_quote!((u8,))), "(u8,)");
+        assert_eq!(name(parse_quote!((u8, [f64; 3]))), "(u8, [f64; 3])");
+        assert_eq!(name(parse_quote!(<T as Trait>::Assoc)), "<T as Trait>::Assoc");
+        assert_eq!(name(parse_quote!(Sum<T, Output = u8>)), "Sum<T, Output = u8>");
+    }
+
+    #[test]
+    fn plain_data() {
+        let t: Ident = parse_quote!(T);
+        let check = |t: syn::Type, type_params: &[&Ident]| {
+            let mut checks = Vec::new();
+            check_plain_data(&t, type_params, &mut checks).map(|()| checks.len()).map_err(|error| error.to_string())
+        };
+        assert_eq!(check(parse_quote!([[u32; 2]; 3]), &[]), Ok(1));
+        assert_eq!(check(parse_quote!(Header), &[]), Ok(1));
+        assert!(check(parse_quote!(T), &[&t]).unwrap_err().starts_with("Type parameters"));
+        assert!(check(parse_quote!([bool; 2]), &[]).unwrap_err().starts_with("`bool` and `char`"));
+        assert_eq!(check(parse_quote!((u8, [u32; 2])), &[]), Ok(2));
+        assert!(check(parse_quote!((u8, (u32, char))), &[]).unwrap_err().starts_with("`bool` and `char`"));
+        assert!(check(parse_quote!(&'static u8), &[]).unwrap_err().contains("references or pointers"));
+        assert!(check(parse_quote!([fn(); 1]), &[]).unwrap_err().contains("references or pointers"));
+        assert!(check(parse_quote!([u8]), &[]).unwrap_err().starts_with("Only primitive numbers"));
     }
 }
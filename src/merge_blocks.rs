@@ -0,0 +1,18 @@
+// Included in the code building the datatypes of structures, and by the unit tests
+
+/// Sort the entries of a typemap by offset, and merge runs of the same datatype that are contiguous in memory into blocks
+///
+/// Entries are (offset, count, size of an element if it is the extent of its datatype, datatype).
+/// Entries are contiguous when the elements of the first one end where the second one starts.
+fn merge_blocks<D: Copy + PartialEq>(typemap: &mut [(usize, usize, Option<usize>, D)]) -> Vec<(usize, usize, Option<usize>, D)> {
+    typemap.sort_by_key(|entry| entry.0);
+    let mut blocks: Vec<(usize, usize, Option<usize>, D)> = Vec::with_capacity(typemap.len());
+    for &entry in typemap.iter() {
+        match blocks.last_mut() {
+            Some(block) if block.3 == entry.3 && block.2 == entry.2
+                && block.2.is_some_and(|size| block.0 + block.1 * size == entry.0) => block.1 += entry.1,
+            _ => blocks.push(entry),
+        }
+    }
+    blocks
+}
@@ -0,0 +1,584 @@
+//! Generation of `#[derive(MpiPack)]`, packing structures with fields of a variable size
+
+use proc_macro2::{Ident, TokenStream};
+use quote::{quote, quote_spanned};
+use syn::spanned::Spanned;
+use syn::{Data, DeriveInput, Index};
+
+use crate::attr::{ContainerAttrs, FieldAttrs};
+use crate::{add_trait_bounds, create_struct_datatype, is_zero_sized, ungroup, unsupported_type, uses_any_ident};
+
+/// Implement the packing functions of a structure
+///
+/// Fields of a fixed size are packed at once, with the datatype of a view of these fields built like
+/// the one of `Equivalence`. The fields of a variable size (`String`, `Vec`, `Box`, and arrays and
+/// tuples of them) follow in the order of declaration, each sequence as its length followed by its
+/// elements. Fields marked `#[mpi(flatten)]` are packed by the functions of their type, which must
+/// derive `MpiPack` too.
+pub fn impl_pack(input: &DeriveInput) -> Result<TokenStream, Vec<syn::Error>> {
+    let data = match input.data {
+        Data::Struct(ref data) => data,
+        _ => return Err(vec![syn::Error::new(input.ident.span(), "`MpiPack` can only be derived for structs")]),
+    };
+    // Attributes only used by `Equivalence` are ignored, so that a type can derive both
+    let (attrs, fields) = match (ContainerAttrs::parse(&input.attrs), FieldAttrs::parse_fields(&data.fields)) {
+        (Ok(attrs), Ok(fields)) => (attrs, fields),
+        (attrs, fields) => return Err(attrs.err().into_iter().chain(fields.err().into_iter().flatten()).collect()),
+    };
+
+    let mut packer = Packer {
+        type_params: input.generics.type_params().map(|param| param.ident.clone()).collect(),
+        leaf_types: Vec::new(),
+        errors: Vec::new(),
+    };
+    let mut fixed = Vec::new();
+    let mut variable = Vec::new();
+    let mut skipped = Vec::new();
+    for (i, (f, field_attrs)) in fields.iter().enumerate() {
+        let member = match f.ident {
+            Some(ref ident) => syn::Member::Named(ident.clone()),
+            None => syn::Member::Unnamed(Index::from(i)),
+        };
+        if field_attrs.skip {
+            skipped.push((member, &f.ty));
+        } else if field_attrs.flatten || is_variable(&f.ty) {
+            if field_attrs.datatype.is_some() || field_attrs.as_type.is_some() {
+                packer.errors.push(syn::Error::new_spanned(&f.ty,
+                    "`as` and `datatype` can only be used on fields of a fixed size, which are packed with a datatype"));
+            }
+            let pack = packer.pack(&f.ty, field_attrs.flatten);
+            let unpack = packer.unpack(&f.ty, field_attrs.flatten);
+            variable.push((member, &f.ty, pack, unpack));
+        } else if !is_zero_sized(&f.ty) {
+            fixed.push(member);
+        }
+    }
+    let (datatype, pack_fixed, unpack_fixed) = match create_struct_datatype(&fields, Some(&fixed)) {
+        _ if fixed.is_empty() => (TokenStream::new(), TokenStream::new(), TokenStream::new()),
+        Ok((datatype, leaf_types)) => {
+            packer.leaf_types.extend(leaf_types);
+            (
+                quote!(let datatype = #datatype;),
+                quote!(unsafe { pack(buffer, self as *const Self as *const ::std::os::raw::c_void, 1, &datatype, comm) };),
+                quote!(unsafe { unpack(buffer, position, pointer as *mut ::std::os::raw::c_void, 1, &datatype, comm)? };),
+            )
+        }
+        Err(errors) => {
+            packer.errors.extend(errors);
+            (TokenStream::new(), TokenStream::new(), TokenStream::new())
+        }
+    };
+    if !packer.errors.is_empty() {
+        return Err(packer.errors);
+    }
+
+    let name = &input.ident;
+    let vis = &input.vis;
+    let mut generics = add_trait_bounds(&input.generics, &attrs, &packer.leaf_types);
+    if attrs.bound.is_none() {
+        let defaults = skipped.iter()
+            .filter(|(_, t)| uses_any_ident(quote!(#t), &packer.type_params))
+            .map(|(_, t)| -> syn::WherePredicate { syn::parse_quote!(#t: ::core::default::Default) })
+            .collect::<Vec<_>>();
+        generics.make_where_clause().predicates.extend(defaults);
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let pack_variable = variable.iter().map(|(member, _, pack, _)| quote! {{
+        let value = &self.#member;
+        #pack
+    }});
+    // The fields are unpacked before being written, so that they are dropped if a later one is invalid
+    let names = (0..variable.len()).map(|i| Ident::new(&format!("field_{}", i), proc_macro2::Span::call_site())).collect::<Vec<_>>();
+    let unpack_variable = variable.iter().zip(&names).map(|((_, t, _, unpack), name)| quote! {
+        let #name: #t = #unpack;
+    });
+    let write_variable = variable.iter().zip(&names).map(|((member, ..), name)| quote! {
+        ::core::ptr::addr_of_mut!((*pointer).#member).write_unaligned(#name);
+    });
+    let write_skipped = skipped.iter().map(|(member, t)| quote! {
+        ::core::ptr::addr_of_mut!((*pointer).#member).write_unaligned(<#t as ::core::default::Default>::default());
+    });
+    let (pack_helpers, unpack_helpers) = helpers();
+
+    // Arguments are `impl Trait`, as type parameters could clash with the ones of the structure, and the
+    // datatype is built outside of the scope of the helpers, which would shadow the functions of the fields
+    let hidden = quote! {
+        #[doc(hidden)]
+        // The buffer is only extended by types with fields to pack
+        #[allow(dead_code, unused_variables, clippy::ptr_arg)]
+        #vis fn __mpi_pack(&self, buffer: &mut Vec<u8>, comm: &impl mpi::topology::Communicator) {
+            #datatype
+            {
+                #pack_helpers
+                #pack_fixed
+                #(#pack_variable)*
+            }
+        }
+
+        #[doc(hidden)]
+        #[allow(dead_code, unused_variables, unused_mut)]
+        #vis fn __mpi_unpack(buffer: &[u8], position: &mut usize, comm: &impl mpi::topology::Communicator) -> Option<Self> {
+            #datatype
+            {
+                #unpack_helpers
+                let mut value = ::core::mem::MaybeUninit::<Self>::uninit();
+                let pointer = value.as_mut_ptr();
+                #unpack_fixed
+                #(#unpack_variable)*
+                // Fields of packed structures may be unaligned
+                unsafe {
+                    #(#write_variable)*
+                    #(#write_skipped)*
+                    Some(value.assume_init())
+                }
+            }
+        }
+    };
+
+    let bytes = quote! {
+        /// Pack the value with `MPI_Pack`, the sequences of its fields of a variable size as their length and elements
+        #vis fn to_packed(&self, comm: &impl mpi::topology::Communicator) -> Vec<u8> {
+            let mut buffer = Vec::new();
+            self.__mpi_pack(&mut buffer, comm);
+            buffer
+        }
+
+        /// Unpack a value packed by `to_packed()` with `MPI_Unpack`
+        ///
+        /// Returns `None` if the bytes are truncated, followed by other bytes, or contain strings that are not UTF-8.
+        #vis fn from_packed(bytes: &[u8], comm: &impl mpi::topology::Communicator) -> Option<Self> {
+            let mut position = 0;
+            let value = Self::__mpi_unpack(bytes, &mut position, comm)?;
+            if position == bytes.len() {
+                Some(value)
+            } else {
+                None
+            }
+        }
+    };
+    let messages = quote! {
+        /// Send the packed value to `destination`, as a message of bytes
+        #vis fn send_packed(&self, destination: &impl mpi::point_to_point::Destination) {
+            let bytes = self.to_packed(mpi::topology::AsCommunicator::as_communicator(destination));
+            mpi::point_to_point::Destination::send(destination, &bytes[..]);
+        }
+
+        /// Receive a value sent by `send_packed()`, probing the size of the message before receiving it
+        ///
+        /// The value is `None` if the message cannot be unpacked, as for `from_packed()`.
+        #vis fn receive_packed(source: &impl mpi::point_to_point::Source) -> (Option<Self>, mpi::point_to_point::Status) {
+            let (message, status) = mpi::point_to_point::Source::matched_probe(source);
+            let mut bytes = vec![0u8; status.count(<u8 as mpi::datatype::Equivalence>::equivalent_datatype()) as usize];
+            let status = message.matched_receive_into(&mut bytes[..]);
+            (Self::from_packed(&bytes, mpi::topology::AsCommunicator::as_communicator(source)), status)
+        }
+    };
+
+    Ok(quote! {
+        impl #impl_generics #name #ty_generics #where_clause {
+            #hidden
+            #bytes
+            #messages
+        }
+    })
+}
+
+/// Generate the functions packing and unpacking runs of values and lengths, used by the generated code
+///
+/// Lengths are packed as `u64`. Before unpacking, the remaining bytes are compared to the size of the
+/// data, which is the packed size on homogeneous systems, so that lengths cannot exhaust the memory.
+fn helpers() -> (TokenStream, TokenStream) {
+    let count = quote! {
+        fn count(length: usize) -> ::std::os::raw::c_int {
+            ::core::convert::TryFrom::try_from(length).unwrap_or_else(|_| panic!(
+                "Packing {} elements or bytes exceeds the range of the MPI count type",
+                length,
+            ))
+        }
+    };
+    let pack = quote! {
+        #count
+
+        unsafe fn pack(
+            buffer: &mut Vec<u8>,
+            values: *const ::std::os::raw::c_void,
+            length: usize,
+            datatype: &impl mpi::datatype::Datatype,
+            comm: &impl mpi::topology::Communicator,
+        ) {
+            let mut size = 0;
+            mpi::ffi::MPI_Pack_size(count(length), mpi::raw::AsRaw::as_raw(datatype), mpi::raw::AsRaw::as_raw(comm), &mut size);
+            let mut position = count(buffer.len());
+            buffer.resize(buffer.len() + size as usize, 0);
+            mpi::ffi::MPI_Pack(
+                values,
+                count(length),
+                mpi::raw::AsRaw::as_raw(datatype),
+                buffer.as_mut_ptr() as *mut ::std::os::raw::c_void,
+                count(buffer.len()),
+                &mut position,
+                mpi::raw::AsRaw::as_raw(comm),
+            );
+            buffer.truncate(position as usize);
+        }
+
+        fn pack_values<T: mpi::datatype::Equivalence>(buffer: &mut Vec<u8>, values: &[T], comm: &impl mpi::topology::Communicator) {
+            let datatype = T::equivalent_datatype();
+            unsafe { pack(buffer, values.as_ptr() as *const ::std::os::raw::c_void, values.len(), &datatype, comm) };
+        }
+
+        fn pack_len(buffer: &mut Vec<u8>, length: usize, comm: &impl mpi::topology::Communicator) {
+            pack_values(buffer, &[length as u64], comm);
+        }
+    };
+    let unpack = quote! {
+        #count
+
+        fn fits(buffer: &[u8], position: usize, length: usize, datatype: &impl mpi::datatype::Datatype) -> bool {
+            let mut size = 0;
+            unsafe { mpi::ffi::MPI_Type_size(mpi::raw::AsRaw::as_raw(datatype), &mut size) };
+            (size as usize).checked_mul(length).is_some_and(|size| size <= buffer.len() - position)
+        }
+
+        unsafe fn unpack(
+            buffer: &[u8],
+            position: &mut usize,
+            values: *mut ::std::os::raw::c_void,
+            length: usize,
+            datatype: &impl mpi::datatype::Datatype,
+            comm: &impl mpi::topology::Communicator,
+        ) -> Option<()> {
+            if !fits(buffer, *position, length, datatype) {
+                return None;
+            }
+            let mut packed_position = count(*position);
+            mpi::ffi::MPI_Unpack(
+                buffer.as_ptr() as *const ::std::os::raw::c_void,
+                count(buffer.len()),
+                &mut packed_position,
+                values,
+                count(length),
+                mpi::raw::AsRaw::as_raw(datatype),
+                mpi::raw::AsRaw::as_raw(comm),
+            );
+            *position = packed_position as usize;
+            Some(())
+        }
+    };
+    let unpack_typed = quote! {
+        fn unpack_vec<T: mpi::datatype::Equivalence>(
+            buffer: &[u8],
+            position: &mut usize,
+            length: usize,
+            comm: &impl mpi::topology::Communicator,
+        ) -> Option<Vec<T>> {
+            let datatype = T::equivalent_datatype();
+            if !fits(buffer, *position, length, &datatype) {
+                return None;
+            }
+            let mut values = Vec::with_capacity(length);
+            unsafe {
+                unpack(buffer, position, values.as_mut_ptr() as *mut ::std::os::raw::c_void, length, &datatype, comm)?;
+                values.set_len(length);
+            }
+            Some(values)
+        }
+
+        fn unpack_value<T: mpi::datatype::Equivalence>(buffer: &[u8], position: &mut usize, comm: &impl mpi::topology::Communicator) -> Option<T> {
+            let mut value = ::core::mem::MaybeUninit::<T>::uninit();
+            unsafe {
+                unpack(buffer, position, value.as_mut_ptr() as *mut ::std::os::raw::c_void, 1, &T::equivalent_datatype(), comm)?;
+                Some(value.assume_init())
+            }
+        }
+
+        fn unpack_len(buffer: &[u8], position: &mut usize, comm: &impl mpi::topology::Communicator) -> Option<usize> {
+            ::core::convert::TryFrom::try_from(unpack_value::<u64>(buffer, position, comm)?).ok()
+        }
+    };
+    (pack, quote!(#unpack #unpack_typed))
+}
+
+/// Generation of the code packing and unpacking the fields of a variable size
+struct Packer {
+    type_params: Vec<Ident>,
+    /// Types implementing the Equivalence trait used by the generated code
+    leaf_types: Vec<syn::Type>,
+    errors: Vec<syn::Error>,
+}
+
+impl Packer {
+    /// Generate code packing `value: &T` for a type `t`
+    fn pack(&mut self, t: &syn::Type, flatten: bool) -> TokenStream {
+        let t = ungroup(t);
+        if is_zero_sized(t) {
+            return TokenStream::new();
+        }
+        let code = match sequence(t) {
+            Some(Sequence::Str) => quote! {
+                pack_len(buffer, value.len(), comm);
+                pack_values(buffer, value.as_bytes(), comm);
+            },
+            Some(Sequence::Slice(elem)) => {
+                let elems = if self.is_leaf(elem, flatten) {
+                    quote!(pack_values(buffer, values, comm);)
+                } else {
+                    let elem_code = self.pack(elem, flatten);
+                    quote! {
+                        for value in values {
+                            #elem_code
+                        }
+                    }
+                };
+                quote! {
+                    let values: &[#elem] = value;
+                    pack_len(buffer, values.len(), comm);
+                    #elems
+                }
+            }
+            Some(Sequence::Box(elem)) => {
+                let elem_code = self.pack(elem, flatten);
+                quote! {
+                    let value: &#elem = value;
+                    #elem_code
+                }
+            }
+            None => match t {
+                syn::Type::Array(array) => {
+                    let elem = ungroup(&array.elem);
+                    if self.is_leaf(elem, flatten) {
+                        quote!(pack_values(buffer, &value[..], comm);)
+                    } else {
+                        let elem_code = self.pack(elem, flatten);
+                        quote! {
+                            for value in value.iter() {
+                                #elem_code
+                            }
+                        }
+                    }
+                }
+                syn::Type::Tuple(tuple) => {
+                    let elems = tuple.elems.iter().enumerate().map(|(i, t)| {
+                        let index = Index::from(i);
+                        let elem_code = self.pack(t, flatten);
+                        quote! {{
+                            let value = &value.#index;
+                            #elem_code
+                        }}
+                    }).collect::<Vec<_>>();
+                    quote! { #(#elems)* }
+                }
+                syn::Type::Path(_) | syn::Type::Macro(_) if flatten => {
+                    self.flattened(t);
+                    quote_spanned! { t.span() => <#t>::__mpi_pack(value, buffer, comm); }
+                }
+                syn::Type::Path(_) | syn::Type::Macro(_) => {
+                    self.leaf(t);
+                    quote_spanned! { t.span() => pack_values(buffer, ::core::slice::from_ref(value), comm); }
+                }
+                _ => {
+                    self.errors.push(unsupported_type(t));
+                    TokenStream::new()
+                }
+            },
+        };
+        quote! {{ #code }}
+    }
+
+    /// Generate an expression unpacking a value of type `t`, returning `None` from the function if it fails
+    fn unpack(&mut self, t: &syn::Type, flatten: bool) -> TokenStream {
+        let t = ungroup(t);
+        if is_zero_sized(t) {
+            return quote!(<#t as ::core::default::Default>::default());
+        }
+        match sequence(t) {
+            Some(Sequence::Str) => {
+                let string = quote! {{
+                    let length = unpack_len(buffer, position, comm)?;
+                    String::from_utf8(unpack_vec::<u8>(buffer, position, length, comm)?).ok()?
+                }};
+                match t {
+                    syn::Type::Path(path) if path.path.segments.last().is_some_and(|segment| segment.value().ident == "String") => string,
+                    _ => quote!(#string.into_boxed_str()),
+                }
+            }
+            Some(Sequence::Slice(elem)) => {
+                let values = if self.is_leaf(elem, flatten) {
+                    quote!(unpack_vec::<#elem>(buffer, position, length, comm)?)
+                } else {
+                    let elem_code = self.unpack(elem, flatten);
+                    // Each element takes some bytes, so the capacity is bounded by the remaining bytes
+                    quote! {{
+                        let mut values: Vec<#elem> = Vec::with_capacity(length.min(buffer.len() - *position));
+                        for _ in 0..length {
+                            values.push(#elem_code);
+                        }
+                        values
+                    }}
+                };
+                let vec = quote! {{
+                    let length = unpack_len(buffer, position, comm)?;
+                    #values
+                }};
+                match t {
+                    syn::Type::Path(path) if path.path.segments.last().is_some_and(|segment| segment.value().ident == "Vec") => vec,
+                    _ => quote!(#vec.into_boxed_slice()),
+                }
+            }
+            Some(Sequence::Box(elem)) => {
+                let elem_code = self.unpack(elem, flatten);
+                quote!(::std::boxed::Box::new(#elem_code))
+            }
+            None => match t {
+                syn::Type::Array(array) => {
+                    let elem = ungroup(&array.elem);
+                    let len = &array.len;
+                    let values = if self.is_leaf(elem, flatten) {
+                        quote!(unpack_vec::<#elem>(buffer, position, #len, comm)?)
+                    } else {
+                        let elem_code = self.unpack(elem, flatten);
+                        quote! {{
+                            let mut values: Vec<#elem> = Vec::with_capacity(#len);
+                            for _ in 0..#len {
+                                values.push(#elem_code);
+                            }
+                            values
+                        }}
+                    };
+                    quote! {{
+                        let array: #t = ::core::convert::TryFrom::try_from(#values).ok()?;
+                        array
+                    }}
+                }
+                syn::Type::Tuple(tuple) => {
+                    let elems = tuple.elems.iter().map(|t| self.unpack(t, flatten)).collect::<Vec<_>>();
+                    quote!((#(#elems,)*))
+                }
+                syn::Type::Path(_) | syn::Type::Macro(_) if flatten => {
+                    quote_spanned! { t.span() => <#t>::__mpi_unpack(buffer, position, comm)? }
+                }
+                syn::Type::Path(_) | syn::Type::Macro(_) => {
+                    quote_spanned! { t.span() => unpack_value::<#t>(buffer, position, comm)? }
+                }
+                // Reported when packing
+                _ => TokenStream::new(),
+            },
+        }
+    }
+
+    /// Whether values of type `t` are packed with its datatype, and runs of them at once
+    fn is_leaf(&mut self, t: &syn::Type, flatten: bool) -> bool {
+        let t = ungroup(t);
+        match t {
+            syn::Type::Path(_) | syn::Type::Macro(_) if !flatten && !is_zero_sized(t) && sequence(t).is_none() => {
+                self.leaf(t);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn leaf(&mut self, t: &syn::Type) {
+        if uses_any_ident(quote!(#t), &self.type_params) {
+            self.leaf_types.push(t.clone());
+        }
+    }
+
+    /// The bounds of a flattened type are unknown, so its type arguments are required to implement
+    /// the Equivalence trait, as for `#[mpi(flatten)]` with `Equivalence`
+    fn flattened(&mut self, t: &syn::Type) {
+        if let syn::Type::Path(path) = t {
+            for segment in path.path.segments.iter() {
+                if let syn::PathArguments::AngleBracketed(ref arguments) = segment.arguments {
+                    for argument in arguments.args.iter() {
+                        if let syn::GenericArgument::Type(ref t) = argument {
+                            self.leaf(t);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Types of a variable size, recognised by name
+enum Sequence<'a> {
+    /// `String` or `Box<str>`
+    Str,
+    /// `Vec<T>` or `Box<[T]>`
+    Slice(&'a syn::Type),
+    /// `Box<T>`
+    Box(&'a syn::Type),
+}
+
+fn sequence(t: &syn::Type) -> Option<Sequence<'_>> {
+    let segment = match ungroup(t) {
+        syn::Type::Path(path) if path.qself.is_none() => path.path.segments.last()?.into_value(),
+        _ => return None,
+    };
+    let argument = match segment.arguments {
+        syn::PathArguments::AngleBracketed(ref arguments) if arguments.args.len() == 1 => match arguments.args[0] {
+            syn::GenericArgument::Type(ref t) => Some(ungroup(t)),
+            _ => None,
+        },
+        _ => None,
+    };
+    match (segment.ident.to_string().as_str(), argument) {
+        ("String", None) => Some(Sequence::Str),
+        ("Vec", Some(elem)) => Some(Sequence::Slice(elem)),
+        ("Box", Some(syn::Type::Path(path))) if path.qself.is_none() && path.path.is_ident("str") => Some(Sequence::Str),
+        ("Box", Some(syn::Type::Slice(slice))) => Some(Sequence::Slice(&slice.elem)),
+        ("Box", Some(elem)) => Some(Sequence::Box(elem)),
+        _ => None,
+    }
+}
+
+/// Whether values of type `t` have a variable size, and cannot be described by a datatype
+fn is_variable(t: &syn::Type) -> bool {
+    match ungroup(t) {
+        syn::Type::Array(array) => is_variable(&array.elem),
+        syn::Type::Tuple(tuple) => tuple.elems.iter().any(is_variable),
+        t => sequence(t).is_some(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quote::quote;
+    use syn::parse_quote;
+
+    fn describe(t: syn::Type) -> Option<String> {
+        sequence(&t).map(|sequence| match sequence {
+            Sequence::Str => "str".to_string(),
+            Sequence::Slice(elem) => format!("slice of {}", quote!(#elem)),
+            Sequence::Box(elem) => format!("box of {}", quote!(#elem)),
+        })
+    }
+
+    #[test]
+    fn sequences() {
+        assert_eq!(describe(parse_quote!(String)), Some("str".to_string()));
+        assert_eq!(describe(parse_quote!(std::string::String)), Some("str".to_string()));
+        assert_eq!(describe(parse_quote!(Box<str>)), Some("str".to_string()));
+        assert_eq!(describe(parse_quote!(Vec<f64>)), Some("slice of f64".to_string()));
+        assert_eq!(describe(parse_quote!(Box<[u8]>)), Some("slice of u8".to_string()));
+        assert_eq!(describe(parse_quote!(Box<Vec<u8>>)), Some("box of Vec < u8 >".to_string()));
+        assert_eq!(describe(parse_quote!(Vec<T, A>)), None);
+        assert_eq!(describe(parse_quote!(Vec)), None);
+        assert_eq!(describe(parse_quote!(String<u8>)), None);
+        assert_eq!(describe(parse_quote!([u8; 4])), None);
+        assert_eq!(describe(parse_quote!(<T as Trait>::Vec<u8>)), None);
+    }
+
+    #[test]
+    fn variable_sizes() {
+        assert!(is_variable(&parse_quote!(String)));
+        assert!(is_variable(&parse_quote!([Vec<u8>; 2])));
+        assert!(is_variable(&parse_quote!((u8, Box<u32>))));
+        assert!(!is_variable(&parse_quote!([(u8, f64); 2])));
+        assert!(!is_variable(&parse_quote!(Option<String>)));
+        assert!(!is_variable(&parse_quote!(T)));
+    }
+}
@@ -0,0 +1,179 @@
+//! Generation of the reduction operations of `#[mpi(reduce = op)]` and `#[mpi(reduce_with = fn)]`
+
+use proc_macro2::{Ident, TokenStream};
+use quote::{quote, quote_spanned};
+use syn::spanned::Spanned;
+use syn::{DeriveInput, Index};
+
+use crate::attr::{ContainerAttrs, FieldAttrs};
+use crate::{field_member, is_zero_sized, ungroup, uses_any_ident};
+
+/// Generate an associated function `reduce_operation()` returning a commutative `UserOperation`
+///
+/// The operation combines the fields that are sent, each with its `#[mpi(reduce = op)]`, or whole
+/// values with the function given by `#[mpi(reduce_with = fn)]`. Nothing is generated without
+/// these attributes.
+pub fn impl_reduce_operation(input: &DeriveInput, attrs: &ContainerAttrs, fields: &[(&syn::Field, FieldAttrs)]) -> Result<TokenStream, Vec<syn::Error>> {
+    let mut errors = Vec::new();
+    let mut reduced = Vec::new();
+    let mut missing = Vec::new();
+    let mut skipped = false;
+    let type_params = input.generics.type_params().map(|param| param.ident.clone()).collect::<Vec<_>>();
+    let mut bounds = Vec::new();
+    for (i, (f, field_attrs)) in fields.iter().enumerate() {
+        if field_attrs.skip {
+            skipped = true;
+        } else if is_zero_sized(&f.ty) {
+            continue;
+        }
+        match field_attrs.reduce {
+            Some(ref op) => reduced.push((i, *f, op)),
+            None if !field_attrs.skip => missing.push(f),
+            None => {}
+        }
+    }
+
+    let body = match attrs.reduce_with {
+        Some(ref function) => {
+            if skipped {
+                errors.push(syn::Error::new_spanned(function,
+                    "`reduce_with` cannot be used on types with skipped fields, as they are not initialized in the buffers of the reduction"));
+            }
+            for (_, _, op) in reduced.iter() {
+                errors.push(syn::Error::new(op.span(), "`reduce` cannot be combined with `reduce_with` on the type"));
+            }
+            let call = quote_spanned! { function.span() =>
+                #function(&x, &mut acc_value);
+            };
+            quote! {
+                let (x, mut acc_value) = unsafe {
+                    (::core::mem::ManuallyDrop::new(x.read_unaligned()), ::core::mem::ManuallyDrop::new(acc.read_unaligned()))
+                };
+                #call
+                unsafe { acc.write_unaligned(::core::mem::ManuallyDrop::into_inner(acc_value)) };
+            }
+        }
+        None if reduced.is_empty() => return Ok(TokenStream::new()),
+        None => {
+            for f in missing {
+                errors.push(syn::Error::new_spanned(f,
+                    "Each field that is sent needs `#[mpi(reduce = op)]` when other fields have one"));
+            }
+            let fields = reduced.iter().map(|(i, f, op)| {
+                let member = match f.ident {
+                    Some(ref ident) => quote!(#ident),
+                    None => {
+                        let index = Index::from(*i);
+                        quote!(#index)
+                    }
+                };
+                let reduction = reduce_value(op, &f.ty, &type_params, &mut bounds);
+                quote! {{
+                    let (x, mut acc_field) = unsafe {
+                        (
+                            ::core::mem::ManuallyDrop::new(::core::ptr::addr_of!((*x).#member).read_unaligned()),
+                            ::core::mem::ManuallyDrop::new(::core::ptr::addr_of!((*acc).#member).read_unaligned()),
+                        )
+                    };
+                    {
+                        let (x, acc) = (&*x, &mut *acc_field);
+                        #reduction
+                    }
+                    unsafe { ::core::ptr::addr_of_mut!((*acc).#member).write_unaligned(::core::mem::ManuallyDrop::into_inner(acc_field)) };
+                }}
+            });
+            quote! { #(#fields)* }
+        }
+    };
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let name = &input.ident;
+    let vis = &input.vis;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let doc = format!("Commutative reduction operation of `{}`, for buffers of its datatype", name);
+    let reduced_fields = reduced.iter().map(|(i, f, op)| format!("`{}`: {}", field_member(f, *i), op)).collect::<Vec<_>>();
+    let doc_fields = if reduced_fields.is_empty() {
+        String::new()
+    } else {
+        format!("Fields are reduced with {}", reduced_fields.join(", "))
+    };
+
+    Ok(quote! {
+        impl #impl_generics #name #ty_generics #where_clause {
+            #[doc = #doc]
+            #[doc = ""]
+            #[doc = #doc_fields]
+            // `x + acc` does not require the `AddAssign` trait
+            #[allow(clippy::assign_op_pattern)]
+            #vis fn reduce_operation() -> mpi::collective::UserOperation<'static>
+            where
+                Self: 'static,
+                #(#bounds,)*
+            {
+                mpi::collective::UserOperation::commutative(|x, mut acc| {
+                    let len = x.len();
+                    let x = x.as_ptr() as *const Self;
+                    let acc = acc.as_mut_ptr() as *mut Self;
+                    for i in 0..len {
+                        // Only the fields of the typemap are initialized, so only they are read. The buffers
+                        // are not aligned for packed fields, nor always for over-aligned types, so values are
+                        // copied to locals, which are not dropped as the buffers keep owning them.
+                        let (x, acc) = unsafe { (x.add(i), acc.add(i)) };
+                        #body
+                    }
+                })
+            }
+        }
+    })
+}
+
+/// Generate code combining `x: &T` into `acc: &mut T` for a field of type `t`
+///
+/// Arrays and tuples are reduced element by element, like they are flattened in the typemap.
+/// Leaf types using type parameters get the bounds required by the operation.
+fn reduce_value(op: &Ident, t: &syn::Type, type_params: &[Ident], bounds: &mut Vec<syn::WherePredicate>) -> TokenStream {
+    let t = ungroup(t);
+    match t {
+        syn::Type::Array(array) => {
+            let elem = reduce_value(op, &array.elem, type_params, bounds);
+            quote! {
+                for (x, acc) in x.iter().zip(acc.iter_mut()) {
+                    #elem
+                }
+            }
+        }
+        syn::Type::Tuple(tuple) => {
+            let elems = tuple.elems.iter().enumerate().map(|(i, t)| {
+                let index = Index::from(i);
+                let elem = reduce_value(op, t, type_params, bounds);
+                quote! {{
+                    let (x, acc) = (&x.#index, &mut acc.#index);
+                    #elem
+                }}
+            }).collect::<Vec<_>>();
+            quote! { #(#elems)* }
+        }
+        _ => {
+            let (bound, reduction) = match op.to_string().as_str() {
+                "sum" => (quote!(::core::ops::Add<Output = #t>), quote!(*acc = *x + *acc;)),
+                "prod" => (quote!(::core::ops::Mul<Output = #t>), quote!(*acc = *x * *acc;)),
+                "max" => (quote!(::core::cmp::PartialOrd), quote!(if *x > *acc { *acc = *x; })),
+                "min" => (quote!(::core::cmp::PartialOrd), quote!(if *x < *acc { *acc = *x; })),
+                "band" => (quote!(::core::ops::BitAnd<Output = #t>), quote!(*acc = *x & *acc;)),
+                "bor" => (quote!(::core::ops::BitOr<Output = #t>), quote!(*acc = *x | *acc;)),
+                "bxor" => (quote!(::core::ops::BitXor<Output = #t>), quote!(*acc = *x ^ *acc;)),
+                // Logical operations are only defined on `bool`
+                "land" => return quote_spanned! { t.span() => *acc = *x && *acc; },
+                "lor" => return quote_spanned! { t.span() => *acc = *x || *acc; },
+                // Keep the value of the accumulator, for fields that are equal on all processes
+                _ => return TokenStream::new(),
+            };
+            if uses_any_ident(quote!(#t), type_params) {
+                bounds.push(syn::parse_quote!(#t: ::core::marker::Copy + #bound));
+            }
+            quote_spanned! { t.span() => #reduction }
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//! Generation of the versioned datatypes of `#[mpi(since = N)]` and `#[mpi(version = N)]`
+
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{DeriveInput, Index};
+
+use crate::attr::{ContainerAttrs, FieldAttrs};
+use crate::{add_trait_bounds, has_repr, is_zero_sized, subset_datatype, uses_any_ident};
+
+/// Generate `MPI_VERSION`, `equivalent_datatype_for_version()` and `fill_missing_fields()`
+///
+/// Fields marked `#[mpi(since = N)]` are only part of the datatypes of versions `N` and later, so
+/// that a process can exchange values with processes built with an older layout, once they agreed
+/// on a version. Nothing is generated without these attributes.
+pub fn impl_versions(input: &DeriveInput, attrs: &ContainerAttrs, fields: &[(&syn::Field, FieldAttrs)], leaf_types: &[syn::Type]) -> Result<TokenStream, Vec<syn::Error>> {
+    let mut errors = Vec::new();
+    // Members sent and fields received, with the version in which they were added
+    let mut members = Vec::new();
+    let mut added = Vec::new();
+    for (i, (f, field_attrs)) in fields.iter().enumerate() {
+        if let (None, Some(default)) = (&field_attrs.since, &field_attrs.default) {
+            errors.push(syn::Error::new_spanned(default, "`default` is only used for fields with `since`"));
+        }
+        if field_attrs.skip || is_zero_sized(&f.ty) {
+            continue;
+        }
+        let since = field_attrs.since.as_ref().map_or(1, |since| since.value() as u32);
+        if let (Some(version), Some(field_since)) = (&attrs.version, &field_attrs.since) {
+            if field_since.value() > version.value() {
+                errors.push(syn::Error::new(field_since.span(),
+                    format!("Field added in version {}, after the version {} of the type", field_since.value(), version.value())));
+            }
+        }
+        let member = match f.ident {
+            Some(ref ident) => syn::Member::Named(ident.clone()),
+            None => syn::Member::Unnamed(Index::from(i)),
+        };
+        members.push((since, member.clone()));
+        if since > 1 {
+            added.push((since, member, f, &field_attrs.default));
+        }
+    }
+    if attrs.version.is_none() && added.is_empty() {
+        return Ok(TokenStream::new());
+    }
+    // The fields of other representations may be reordered when one is added
+    if !has_repr(&input.attrs, "C") && !has_repr(&input.attrs, "transparent") {
+        let since = fields.iter().filter_map(|(_, field_attrs)| field_attrs.since.as_ref()).find(|since| since.value() > 1);
+        let span = attrs.version.as_ref().or(since).map_or_else(|| input.ident.span(), |version| version.span());
+        errors.push(syn::Error::new(span, "Versioned structs must be `#[repr(C)]`, as Rust may reorder their fields when one is added"));
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let mut versions = added.iter().map(|(since, ..)| *since).collect::<Vec<_>>();
+    versions.sort_unstable_by(|a, b| b.cmp(a));
+    versions.dedup();
+    let latest = attrs.version.as_ref().map_or_else(|| versions.first().cloned().unwrap_or(1), |version| version.value() as u32);
+
+    // The datatype of each version in which fields were added, from the latest
+    let mut datatypes = Vec::new();
+    for version in versions.iter().chain(std::iter::once(&1)) {
+        let version_members = members.iter().filter(|(since, _)| since <= version).map(|(_, member)| member.clone()).collect::<Vec<_>>();
+        let (datatype, _) = subset_datatype(fields, &version_members);
+        datatypes.push(if *version == 1 {
+            datatype
+        } else {
+            quote! {
+                if version >= #version {
+                    return #datatype;
+                }
+            }
+        });
+    }
+
+    let type_params = input.generics.type_params().map(|param| param.ident.clone()).collect::<Vec<_>>();
+    let mut bounds = Vec::new();
+    let fills = added.iter().map(|(since, member, f, default)| {
+        let field_type = &f.ty;
+        let value = match default {
+            Some(function) => quote!(#function()),
+            None => {
+                if uses_any_ident(quote!(#field_type), &type_params) {
+                    bounds.push(quote!(#field_type: ::core::default::Default));
+                }
+                quote!(<#field_type as ::core::default::Default>::default())
+            }
+        };
+        quote! {
+            if version < #since {
+                self.#member = #value;
+            }
+        }
+    }).collect::<Vec<_>>();
+
+    let name = &input.ident;
+    let vis = &input.vis;
+    let generics = add_trait_bounds(&input.generics, attrs, leaf_types);
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let doc = format!("Version of the layout of `{}`, whose datatype is the equivalent datatype", name);
+    let type_name = name.to_string();
+
+    Ok(quote! {
+        impl #impl_generics #name #ty_generics #where_clause {
+            #[doc = #doc]
+            #vis const MPI_VERSION: u32 = #latest;
+
+            /// Datatype of the fields present in `version` of the layout, with the extent of the type
+            ///
+            /// Values received with an older version have no value for the newer fields, which can be
+            /// set with `fill_missing_fields()`. Panics if the version is not between 1 and `MPI_VERSION`.
+            #vis fn equivalent_datatype_for_version(version: u32) -> mpi::datatype::UserDatatype {
+                assert!(
+                    (1..=Self::MPI_VERSION).contains(&version),
+                    "`{}` has no version {}, its versions are 1 to {}",
+                    #type_name, version, Self::MPI_VERSION,
+                );
+                #(#datatypes)*
+            }
+
+            /// Set the fields added after `version` to their default value, after receiving with its datatype
+            #vis fn fill_missing_fields(&mut self, version: u32)
+            where
+                #(#bounds,)*
+            {
+                #(#fills)*
+                let _ = version;
+            }
+        }
+    })
+}
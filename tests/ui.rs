@@ -0,0 +1,7 @@
+// Errors reported by the derive macro with their spans, and inputs whose generated code compiles
+#[test]
+fn ui() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/*.rs");
+    t.pass("tests/ui/pass/*.rs");
+}
@@ -0,0 +1,27 @@
+use mpi_derive::Equivalence;
+
+#[derive(Equivalence)]
+struct Unknown {
+    #[mpi(skp)]
+    a: u8,
+}
+
+#[derive(Equivalence)]
+struct Conflicts {
+    #[mpi(skip, as = u32)]
+    a: u64,
+    #[mpi(reduce = sum, skip)]
+    b: u64,
+    #[mpi(reduce = avg)]
+    c: u64,
+}
+
+#[derive(Equivalence)]
+#[mpi(cached, transparent)]
+struct CachedTransparent(u8);
+
+#[derive(Equivalence)]
+#[mpi(bytes, field_datatypes)]
+struct BytesViews(u8);
+
+fn main() {}
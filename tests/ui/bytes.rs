@@ -0,0 +1,14 @@
+use mpi_derive::Equivalence;
+
+#[derive(Equivalence, Clone, Copy)]
+#[mpi(bytes)]
+struct NotPlainData<T: Copy> {
+    pair: (u8, (u32, char)),
+    flags: [bool; 2],
+    value: T,
+    pointer: [*const u8; 2],
+    #[mpi(skip)]
+    skipped: u8,
+}
+
+fn main() {}
@@ -0,0 +1,21 @@
+use mpi_derive::Equivalence;
+
+#[derive(Equivalence)]
+enum NoRepr {
+    A,
+}
+
+#[derive(Equivalence)]
+#[repr(u128)]
+enum Wide {
+    A,
+}
+
+#[derive(Equivalence)]
+#[repr(u8)]
+#[mpi(cached, bound = "")]
+enum Attributes {
+    A,
+}
+
+fn main() {}
@@ -0,0 +1,17 @@
+use mpi_derive::Equivalence;
+
+#[derive(Equivalence)]
+#[repr(u8)]
+enum Payloads<T> {
+    Text(String),
+    Name(&'static str),
+    Value(T),
+    #[mpi(skip)]
+    Skipped,
+    Field {
+        #[mpi(as = u8)]
+        value: u8,
+    },
+}
+
+fn main() {}
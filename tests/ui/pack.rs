@@ -0,0 +1,16 @@
+use mpi_derive::MpiPack;
+
+#[derive(MpiPack)]
+enum NotStruct {
+    A,
+}
+
+#[derive(MpiPack)]
+struct Fields<'a> {
+    #[mpi(as = u8)]
+    lengths: Vec<u8>,
+    name: &'a str,
+    pointers: Vec<*const u8>,
+}
+
+fn main() {}
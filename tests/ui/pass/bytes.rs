@@ -0,0 +1,20 @@
+use mpi::datatype::Equivalence;
+use mpi_derive::Equivalence;
+
+// Padding bytes are sent with the fields
+#[derive(Equivalence, Clone, Copy)]
+#[mpi(bytes)]
+struct Padded {
+    tag: u8,
+    value: u64,
+    pair: (u16, [u8; 3]),
+}
+
+#[derive(Equivalence, Clone, Copy)]
+#[mpi(bytes)]
+struct Nested(Padded, [Padded; 2]);
+
+fn main() {
+    let _: fn() -> _ = <Padded as Equivalence>::equivalent_datatype;
+    let _: fn() -> _ = <Nested as Equivalence>::equivalent_datatype;
+}
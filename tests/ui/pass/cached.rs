@@ -0,0 +1,30 @@
+use mpi::datatype::{DatatypeRef, Equivalence};
+use mpi_derive::Equivalence;
+
+// A field type with the name of the cache of the datatypes
+#[derive(Equivalence, Clone, Copy)]
+#[mpi(cached)]
+struct Cached {
+    id: u32,
+    weight: f64,
+}
+
+#[derive(Equivalence)]
+#[mpi(cached)]
+struct Entry {
+    key: Cached,
+    values: [Cached; 2],
+}
+
+#[derive(Equivalence)]
+#[mpi(cached)]
+struct Buffer<T, const N: usize> {
+    values: [T; N],
+    len: u8,
+}
+
+fn main() {
+    let _: fn() -> DatatypeRef<'static> = <Entry as Equivalence>::equivalent_datatype;
+    let _: fn() -> DatatypeRef<'static> = <Buffer<Cached, 4> as Equivalence>::equivalent_datatype;
+    let _: fn() -> DatatypeRef<'static> = <Buffer<f32, 0> as Equivalence>::equivalent_datatype;
+}
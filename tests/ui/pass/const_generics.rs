@@ -0,0 +1,23 @@
+use mpi::datatype::Equivalence;
+use mpi_derive::Equivalence;
+
+#[derive(Equivalence)]
+struct Block<const N: usize> {
+    data: [f64; N],
+}
+
+#[derive(Equivalence)]
+struct Grid<T, const W: usize, const H: usize> {
+    cells: [[T; W]; H],
+    rows: [(u8, T); H],
+}
+
+fn datatype<T: Equivalence>() -> T::Out {
+    T::equivalent_datatype()
+}
+
+fn main() {
+    let _ = datatype::<Block<4>> as fn() -> _;
+    let _ = datatype::<Block<0>> as fn() -> _;
+    let _ = datatype::<Grid<u16, 3, 2>> as fn() -> _;
+}
@@ -0,0 +1,35 @@
+use std::marker::PhantomData;
+
+use mpi::datatype::Equivalence;
+use mpi_derive::Equivalence;
+
+// A field type with the name of the type checking the tag of `#[repr(C)]` enums
+#[derive(Equivalence, Clone, Copy)]
+#[mpi(bytes)]
+struct Tag {
+    id: u32,
+}
+
+#[derive(Equivalence, Clone, Copy)]
+#[repr(C)]
+enum Event {
+    Labelled(Tag),
+    Moved { by: [f64; 3] },
+    Empty,
+}
+
+const BASE: u8 = 4;
+
+// A type parameter with the name of the one of `receive_checked` in previous versions
+#[derive(Equivalence)]
+#[repr(C, u8)]
+enum Tagged<S> {
+    Value(u32, PhantomData<S>),
+    Empty = BASE,
+    Last,
+}
+
+fn main() {
+    let _: fn() -> _ = <Event as Equivalence>::equivalent_datatype;
+    let _: fn() -> _ = <Tagged<String> as Equivalence>::equivalent_datatype;
+}
@@ -0,0 +1,25 @@
+use mpi::datatype::Equivalence;
+use mpi_derive::Equivalence;
+
+#[derive(Equivalence, Debug, PartialEq)]
+#[repr(i16)]
+enum Level {
+    Low = -1,
+    Mid,
+    High = 10,
+}
+
+#[derive(Equivalence, Debug, PartialEq)]
+#[repr(C)]
+enum Signal {
+    Start,
+    Stop,
+}
+
+fn main() {
+    assert_eq!(Level::from_discriminant(0), Some(Level::Mid));
+    assert_eq!(Level::from_discriminant(1), None);
+    assert_eq!(Signal::from_discriminant(1), Some(Signal::Stop));
+    let _: fn() -> _ = <Level as Equivalence>::equivalent_datatype;
+    let _: fn() -> _ = <Signal as Equivalence>::equivalent_datatype;
+}
@@ -0,0 +1,27 @@
+use mpi_derive::Equivalence;
+
+// Type parameters named like the parameters of the generated functions
+#[derive(Equivalence)]
+struct Pair<C> {
+    a: C,
+    b: C,
+}
+
+// Fields of derived types hash their fingerprint, whatever their path
+#[derive(Equivalence)]
+struct Nested<T> {
+    pair: Pair<T>,
+    pairs: [self::Pair<u8>; 2],
+    value: std::primitive::u32,
+}
+
+fn check<C: mpi::topology::Communicator>(comm: &C) {
+    Pair::<f64>::check_fingerprint(comm).unwrap();
+    Nested::<i16>::check_fingerprint(comm).unwrap();
+}
+
+fn main() {
+    let _ = check::<mpi::topology::SystemCommunicator> as fn(&_);
+    let _ = Pair::<u8>::MPI_FINGERPRINT;
+    let _ = Nested::<u8>::MPI_FINGERPRINT;
+}
@@ -0,0 +1,27 @@
+use mpi::datatype::Equivalence;
+use mpi_derive::Equivalence;
+
+// Type parameters named like the type parameter of the generated function
+#[derive(Equivalence)]
+struct Pair<R> {
+    a: R,
+    b: R,
+}
+
+#[derive(Equivalence)]
+struct Outer<R> {
+    #[mpi(flatten)]
+    pair: Pair<R>,
+    #[mpi(flatten)]
+    bytes: Pair<u8>,
+    last: R,
+}
+
+fn datatype<T: Equivalence>() -> T::Out {
+    T::equivalent_datatype()
+}
+
+fn main() {
+    let _ = datatype::<Outer<f64>> as fn() -> _;
+    let _ = datatype::<Outer<Pair<i32>>> as fn() -> _;
+}
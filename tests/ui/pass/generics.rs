@@ -0,0 +1,39 @@
+use std::marker::PhantomData;
+
+use mpi::datatype::Equivalence;
+use mpi_derive::Equivalence;
+
+// Bounds are inferred for the type parameters of the fields
+#[derive(Equivalence)]
+struct Pair<T> {
+    a: T,
+    b: T,
+}
+
+// Lifetimes, parameters that are never sent and existing where clauses are kept
+#[derive(Equivalence)]
+struct Tagged<'a, T, U: ?Sized>
+where
+    T: Copy,
+{
+    value: (T, [T; 2]),
+    marker: PhantomData<&'a U>,
+}
+
+// Bounds given by hand replace the inferred ones
+#[derive(Equivalence)]
+#[mpi(bound = "T: Equivalence + Copy")]
+struct Bounded<T> {
+    value: T,
+}
+
+fn datatype<T: Equivalence>() -> T::Out {
+    T::equivalent_datatype()
+}
+
+fn main() {
+    let _ = datatype::<Pair<f64>> as fn() -> _;
+    let _ = datatype::<Pair<Pair<u8>>> as fn() -> _;
+    let _ = datatype::<Tagged<'static, u32, str>> as fn() -> _;
+    let _ = datatype::<Bounded<i16>> as fn() -> _;
+}
@@ -0,0 +1,31 @@
+use std::num::Wrapping;
+
+use mpi::datatype::{Equivalence, UserDatatype};
+use mpi_derive::Equivalence;
+
+// A field type with the name of the type checking the sizes of `as` types
+#[derive(Clone, Copy)]
+struct SameSize(u64);
+
+fn pair() -> UserDatatype {
+    UserDatatype::contiguous(2, &u32::equivalent_datatype())
+}
+
+#[derive(Equivalence)]
+struct Overrides {
+    #[mpi(as = u64)]
+    size: SameSize,
+    #[mpi(as = "u32")]
+    counter: Wrapping<u32>,
+    #[mpi(datatype = pair)]
+    pair: [u32; 2],
+}
+
+#[derive(Equivalence)]
+#[mpi(transparent)]
+struct Newtype(#[mpi(as = u64)] SameSize);
+
+fn main() {
+    let _: fn() -> _ = <Overrides as Equivalence>::equivalent_datatype;
+    let _: fn() -> _ = <Newtype as Equivalence>::equivalent_datatype;
+}
@@ -0,0 +1,36 @@
+use mpi::datatype::{Equivalence, UserDatatype};
+use mpi::traits::*;
+use mpi_derive::MpiPack;
+
+// Type parameters named like the parameters of the generated functions
+#[derive(MpiPack)]
+struct Packed<C, D, S> {
+    values: Vec<C>,
+    value: D,
+    names: (S, String),
+}
+
+// A datatype function with the name of a function packing the fields
+fn count() -> UserDatatype {
+    UserDatatype::contiguous(2, &u32::equivalent_datatype())
+}
+
+#[derive(MpiPack)]
+struct Counted {
+    #[mpi(datatype = count)]
+    counts: [u32; 2],
+    #[mpi(flatten)]
+    packed: Vec<Packed<u8, f64, u16>>,
+}
+
+fn round_trip<C: mpi::topology::Communicator>(comm: &C) {
+    let bytes = Counted { counts: [1, 2], packed: Vec::new() }.to_packed(comm);
+    let _ = Counted::from_packed(&bytes, comm);
+    let packed = Packed { values: vec![1u8], value: 2.0f64, names: (3u16, String::new()) };
+    packed.send_packed(&comm.this_process());
+    let _ = Packed::<u8, f64, u16>::receive_packed(&comm.any_process());
+}
+
+fn main() {
+    let _ = round_trip::<mpi::topology::SystemCommunicator> as fn(&_);
+}
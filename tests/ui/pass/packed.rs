@@ -0,0 +1,22 @@
+use mpi::datatype::Equivalence;
+use mpi_derive::Equivalence;
+
+#[derive(Equivalence, Clone, Copy)]
+#[repr(C, packed)]
+struct Packed {
+    tag: u8,
+    value: f64,
+    count: u32,
+    pair: (u8, u16),
+}
+
+#[derive(Equivalence)]
+#[repr(packed(2))]
+struct Aligned2(u8, u32, [u16; 3]);
+
+fn main() {
+    assert_eq!(Packed::MPI_LAYOUT[1].1, 1);
+    assert_eq!(Packed::MPI_LAYOUT[3].1, 13);
+    let _: fn() -> _ = <Packed as Equivalence>::equivalent_datatype;
+    let _: fn() -> _ = <Aligned2 as Equivalence>::equivalent_datatype;
+}
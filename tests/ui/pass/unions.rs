@@ -0,0 +1,35 @@
+use mpi::datatype::Equivalence;
+use mpi_derive::Equivalence;
+
+#[derive(Equivalence, Clone, Copy)]
+#[mpi(bytes)]
+struct Header {
+    id: u32,
+    flags: u16,
+}
+
+// Sent as bytes, with fields of plain data of different sizes
+#[derive(Equivalence, Clone, Copy)]
+#[repr(C)]
+union Value {
+    int: i64,
+    float: f64,
+    header: Header,
+    bytes: [u8; 3],
+    pair: (u8, u32),
+}
+
+// Sent with the typemap of the active field, which may use type parameters
+#[derive(Equivalence, Clone, Copy)]
+#[repr(C)]
+#[mpi(active = sample)]
+union Slot<T: Copy> {
+    sample: (T, u32),
+    free_list_next: u64,
+}
+
+fn main() {
+    let _: fn() -> _ = <Value as Equivalence>::equivalent_datatype;
+    let _: fn() -> _ = <Slot<f64> as Equivalence>::equivalent_datatype;
+    let _: fn() -> _ = <Slot<Header> as Equivalence>::equivalent_datatype;
+}
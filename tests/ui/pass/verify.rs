@@ -0,0 +1,18 @@
+use mpi::datatype::{Equivalence, UserDatatype};
+use mpi_derive::Equivalence;
+
+// A datatype function with the name of the helper of `verify_equivalence`
+fn extents() -> UserDatatype {
+    UserDatatype::contiguous(2, &f32::equivalent_datatype())
+}
+
+#[derive(Equivalence)]
+struct Bounds {
+    #[mpi(datatype = extents)]
+    extents: [f32; 2],
+    count: u32,
+}
+
+fn main() {
+    let _: fn() -> Result<(), String> = Bounds::verify_equivalence;
+}
@@ -0,0 +1,19 @@
+use mpi_derive::Equivalence;
+
+#[derive(Clone, Copy)]
+struct Foreign(u32);
+
+#[derive(Equivalence, Clone, Copy)]
+#[mpi(bytes)]
+struct Fields {
+    foreign: Foreign,
+    optional: [Option<u8>; 2],
+}
+
+#[derive(Equivalence)]
+#[repr(u8)]
+enum Variants {
+    Text(String),
+}
+
+fn main() {}
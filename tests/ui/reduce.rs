@@ -0,0 +1,21 @@
+use mpi_derive::Equivalence;
+
+#[derive(Equivalence)]
+struct Missing {
+    #[mpi(reduce = sum)]
+    a: u8,
+    b: u8,
+}
+
+#[derive(Equivalence)]
+#[mpi(reduce_with = merge)]
+struct Combined {
+    #[mpi(reduce = sum)]
+    a: u8,
+    #[mpi(skip)]
+    b: u8,
+}
+
+fn merge(_: &Combined, _: &mut Combined) {}
+
+fn main() {}
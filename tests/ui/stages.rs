@@ -0,0 +1,15 @@
+use mpi_derive::Equivalence;
+
+#[derive(Equivalence)]
+#[repr(C)]
+#[mpi(view(Missing = [nope]), reduce_with = combine, version = 1)]
+struct Stages {
+    #[mpi(reduce = sum)]
+    a: *const u8,
+    #[mpi(since = 2)]
+    b: u8,
+}
+
+fn combine(_: &Stages, _: &mut Stages) {}
+
+fn main() {}
@@ -0,0 +1,22 @@
+use mpi_derive::Equivalence;
+
+#[derive(Equivalence)]
+#[mpi(transparent)]
+struct TwoFields {
+    a: u32,
+    b: u8,
+}
+
+#[derive(Equivalence)]
+#[repr(transparent)]
+struct Datatype(#[mpi(datatype = datatype)] u8);
+
+fn datatype() -> mpi::datatype::UserDatatype {
+    unimplemented!()
+}
+
+#[derive(Equivalence)]
+#[mpi(transparent)]
+struct Versioned(#[mpi(since = 2)] u8);
+
+fn main() {}
@@ -0,0 +1,23 @@
+use mpi_derive::Equivalence;
+
+#[derive(Equivalence)]
+union NoRepr {
+    a: u8,
+}
+
+#[derive(Equivalence)]
+#[repr(C)]
+#[mpi(active = c)]
+union MissingActive {
+    a: u8,
+    #[mpi(skip)]
+    b: u16,
+}
+
+#[derive(Equivalence)]
+#[mpi(active = a)]
+struct NotUnion {
+    a: u8,
+}
+
+fn main() {}
@@ -0,0 +1,16 @@
+use mpi_derive::Equivalence;
+
+#[derive(Equivalence)]
+struct Addresses<'a> {
+    reference: &'a u64,
+    pointer: *const u8,
+    function: fn(),
+    nested: (u8, [*mut u8; 2]),
+}
+
+#[derive(Equivalence)]
+struct Slice {
+    values: [u8],
+}
+
+fn main() {}
@@ -0,0 +1,37 @@
+use mpi_derive::Equivalence;
+
+fn zero() -> u8 {
+    0
+}
+
+#[derive(Equivalence)]
+#[repr(C)]
+#[mpi(version = 2)]
+struct Later {
+    a: u8,
+    #[mpi(since = 3)]
+    b: u8,
+    #[mpi(default = zero)]
+    c: u8,
+}
+
+#[derive(Equivalence)]
+#[repr(C)]
+struct Invalid {
+    #[mpi(skip, since = 2)]
+    a: u8,
+    #[mpi(since = 0)]
+    b: u8,
+}
+
+#[derive(Equivalence)]
+#[mpi(version = 2)]
+struct Unordered {
+    a: u8,
+    b: u32,
+}
+
+#[derive(Equivalence)]
+struct Added(u8, #[mpi(since = 2)] u32);
+
+fn main() {}
@@ -0,0 +1,18 @@
+use mpi_derive::Equivalence;
+
+#[derive(Equivalence)]
+#[mpi(view(Subset = [a, a, missing, skipped]))]
+struct Views {
+    a: u8,
+    #[mpi(skip)]
+    skipped: u8,
+}
+
+#[derive(Equivalence)]
+#[mpi(view(Subset = [0]))]
+#[repr(u8)]
+enum NotStruct {
+    A,
+}
+
+fn main() {}